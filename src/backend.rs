@@ -0,0 +1,1158 @@
+//! Rendering backends.
+//!
+//! A backend drives the toolchain that turns the sources inside a prepared build directory into
+//! output files. `TexRender` uses `latexmk` by default, other backends can be selected using
+//! `TexRender::backend`.
+//!
+//! Backends do not spawn processes themselves. Instead, they hand out commands one at a time
+//! through a `BackendRun`, which `TexRender` runs under its timeout, cancellation token and
+//! resource limits, for both blocking and async renderings. Commands are run inside
+//! `RenderJob::work_dir`, with the search path environment already set up; backends only need to
+//! supply the program and its arguments.
+//!
+//! The `Fake` backend runs no tools at all, for testing code built on `TexRender` without a TeX
+//! installation.
+
+use crate::{diagnostics, BibBackend, OutputFormat, RenderingError, ShellEscape, TexEngine};
+use std::{
+    ffi::{OsStr, OsString},
+    fmt, fs, io, path, process,
+    sync::{Arc, Mutex},
+};
+
+/// A rendering to be performed by a backend.
+#[derive(Clone, Debug)]
+pub struct RenderJob<'a> {
+    /// Root of the project inside the build directory, holding all sources.
+    pub project_dir: &'a path::Path,
+    /// Directory containing the main file, all commands are run inside it.
+    ///
+    /// Either `project_dir` or one of its subdirectories.
+    pub work_dir: &'a path::Path,
+    /// Directory holding the assets, if any were added.
+    ///
+    /// Already part of all search paths.
+    pub assets_dir: Option<&'a path::Path>,
+    /// Filename of the main file.
+    pub main_file: &'a OsStr,
+    /// Jobname, which all output files are named after.
+    pub jobname: &'a str,
+    /// Name of the file the backend must produce inside `work_dir`.
+    ///
+    /// For SVG output, this is the DVI file that is converted to SVG afterwards.
+    pub output_file: &'a str,
+    /// TeX engine to use.
+    pub engine: TexEngine,
+    /// Requested output format.
+    pub output_format: OutputFormat,
+    /// Shell escape policy to apply.
+    pub shell_escape: ShellEscape,
+    /// Tool processing the bibliography, if any.
+    pub bibliography: Option<BibBackend>,
+    /// Whether the input is untrusted.
+    pub untrusted: bool,
+    /// Whether the output must be reproducible.
+    ///
+    /// `SOURCE_DATE_EPOCH` and `FORCE_SOURCE_DATE` are already set for all commands, backends
+    /// only need to handle engine-specific settings.
+    pub reproducible: bool,
+    /// Whether to write SyncTeX data to `<jobname>.synctex.gz`.
+    pub synctex: bool,
+    /// Additional arguments for the backend's main command, already validated.
+    pub args: &'a [OsString],
+    /// Additional arguments for every engine pass, already validated.
+    pub engine_args: &'a [OsString],
+    /// Contents of an additional `latexmkrc`, if any.
+    pub latexmkrc: Option<&'a str>,
+}
+
+/// Toolchain turning a prepared build directory into output files.
+pub trait RenderBackend: fmt::Debug + Send + Sync {
+    /// Starts a rendering.
+    ///
+    /// Returns an error if the backend cannot perform the job, e.g. because it does not support
+    /// the requested output format.
+    fn start(&self, job: &RenderJob<'_>) -> Result<Box<dyn BackendRun>, RenderingError>;
+
+    /// Returns a description of the backend and all of its settings that affect the output.
+    ///
+    /// Used to compute cache keys.
+    fn cache_id(&self) -> String;
+
+    /// Returns whether the backend produces SVG pages itself.
+    ///
+    /// If not, SVG output is converted from the DVI file named by `RenderJob::output_file`
+    /// using `dvisvgm`.
+    fn produces_svg(&self) -> bool {
+        false
+    }
+
+    /// Returns the programs the backend runs for a rendering with the given settings.
+    ///
+    /// Programs are given by name, to be looked up on `PATH`, or by path. Used by
+    /// `TexRender::preflight` to check the toolchain; backends running no programs return none.
+    fn programs(
+        &self,
+        engine: TexEngine,
+        output_format: OutputFormat,
+        bibliography: Option<BibBackend>,
+    ) -> Vec<path::PathBuf> {
+        let _ = (engine, output_format, bibliography);
+        Vec::new()
+    }
+}
+
+/// Returns the TeX Live programs run for a rendering with the given settings.
+fn tex_programs(
+    engine: TexEngine,
+    output_format: OutputFormat,
+    bibliography: Option<BibBackend>,
+) -> Vec<&'static str> {
+    let mut programs = vec![match (output_format, engine) {
+        (OutputFormat::Pdf, engine) => engine.executable(),
+        (_, TexEngine::PdfLatex) => "latex",
+        (_, TexEngine::XeLatex) => "xelatex",
+        (_, TexEngine::LuaLatex) => "dvilualatex",
+    }];
+    programs.extend(bibliography.map(BibBackend::executable));
+    if output_format == OutputFormat::PostScript {
+        programs.push("dvips");
+    }
+    programs
+}
+
+/// A rendering in progress.
+pub trait BackendRun: Send {
+    /// Returns the next command to run, or `None` once the output is complete.
+    ///
+    /// `previous` holds the output of the previously returned command, which exited
+    /// successfully. A failing command ends the rendering.
+    fn next_command(
+        &mut self,
+        previous: Option<&process::Output>,
+    ) -> Result<Option<process::Command>, RenderingError>;
+
+    /// Returns the number of TeX engine passes run so far.
+    fn passes(&self) -> usize;
+}
+
+/// Arguments passed to every TeX engine.
+const ENGINE_ARGS: &[&str] = &[
+    "-interaction=nonstopmode",
+    "-halt-on-error",
+    "-file-line-error",
+];
+
+/// Name of the file holding the additional `latexmkrc`, hidden from TeX for untrusted input.
+const LATEXMKRC_FILE: &str = ".texrender-latexmkrc";
+
+/// Returns TeX code making the engine omit the trailer ID and dates from the PDF.
+///
+/// XeTeX has no such settings, its driver derives both from `SOURCE_DATE_EPOCH` instead.
+fn reproducible_pretex(engine: TexEngine) -> Option<&'static str> {
+    match engine {
+        TexEngine::PdfLatex => Some(r"\pdftrailerid{}\pdfinfoomitdate=1\pdfsuppressptexinfo=-1"),
+        TexEngine::LuaLatex => Some(r"\pdfvariable suppressoptionalinfo 1023\relax"),
+        TexEngine::XeLatex => None,
+    }
+}
+
+/// Backend running `latexmk`, which runs the engine and any other tools as often as required.
+#[derive(Clone, Debug)]
+pub struct Latexmk {
+    /// Path to latexmk.
+    path: path::PathBuf,
+}
+
+impl Latexmk {
+    /// Creates a new `latexmk` backend.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the path of `latexmk`.
+    ///
+    /// If not set, will look for `latexmk` on the current `PATH`.
+    pub fn path<P: Into<path::PathBuf>>(&mut self, path: P) -> &mut Self {
+        self.path = path.into();
+        self
+    }
+}
+
+impl Default for Latexmk {
+    fn default() -> Self {
+        Latexmk {
+            path: "latexmk".into(),
+        }
+    }
+}
+
+impl RenderBackend for Latexmk {
+    fn start(&self, job: &RenderJob<'_>) -> Result<Box<dyn BackendRun>, RenderingError> {
+        let mut cmd = process::Command::new(&self.path);
+        cmd.args(ENGINE_ARGS);
+        cmd.args(latexmk_format_args(job)?);
+
+        if job.bibliography.is_some() {
+            cmd.arg("-bibtex");
+        }
+
+        if job.untrusted {
+            cmd.arg("-norc");
+        }
+
+        cmd.arg(job.shell_escape.latexmk_arg());
+
+        if let Some(pretex) = reproducible_pretex(job.engine).filter(|_| job.reproducible) {
+            cmd.arg(format!("-usepretex={}", pretex));
+        }
+
+        if job.synctex {
+            cmd.arg("-synctex=1");
+        }
+
+        if let Some(latexmkrc) = job.latexmkrc {
+            fs::write(job.work_dir.join(LATEXMKRC_FILE), latexmkrc)
+                .map_err(RenderingError::WriteInputFile)?;
+            cmd.args(["-r", LATEXMKRC_FILE]);
+        }
+
+        for arg in job.engine_args {
+            let mut option = OsString::from("-latexoption=");
+            option.push(arg);
+            cmd.arg(option);
+        }
+        cmd.args(job.args);
+        cmd.arg(job.main_file);
+
+        Ok(Box::new(SingleCommand {
+            command: Some(cmd),
+            passes: 0,
+            count_passes: count_latexmk_passes,
+        }))
+    }
+
+    fn cache_id(&self) -> String {
+        format!("latexmk {}", self.path.display())
+    }
+
+    fn programs(
+        &self,
+        engine: TexEngine,
+        output_format: OutputFormat,
+        bibliography: Option<BibBackend>,
+    ) -> Vec<path::PathBuf> {
+        let mut programs = vec![self.path.clone()];
+        programs.extend(
+            tex_programs(engine, output_format, bibliography)
+                .into_iter()
+                .map(path::PathBuf::from),
+        );
+        programs
+    }
+}
+
+/// Returns the `latexmk` switches selecting engine and output format.
+fn latexmk_format_args(job: &RenderJob<'_>) -> Result<&'static [&'static str], RenderingError> {
+    let args: &[&str] = match (job.output_format, job.engine) {
+        (OutputFormat::Pdf, TexEngine::PdfLatex) => &["-pdf"],
+        (OutputFormat::Pdf, TexEngine::XeLatex) => &["-pdfxe"],
+        (OutputFormat::Pdf, TexEngine::LuaLatex) => &["-pdflua"],
+        (OutputFormat::Dvi, TexEngine::PdfLatex) | (OutputFormat::Svg, TexEngine::PdfLatex) => {
+            &["-dvi"]
+        }
+        (OutputFormat::Dvi, TexEngine::XeLatex) | (OutputFormat::Svg, TexEngine::XeLatex) => {
+            &["-xdv"]
+        }
+        (OutputFormat::Dvi, TexEngine::LuaLatex) | (OutputFormat::Svg, TexEngine::LuaLatex) => {
+            &["-dvilua"]
+        }
+        (OutputFormat::PostScript, TexEngine::PdfLatex) => &["-ps"],
+        (OutputFormat::PostScript, TexEngine::LuaLatex) => &["-dvilua", "-ps"],
+        (OutputFormat::PostScript, TexEngine::XeLatex) => return Err(unsupported_format(job)),
+    };
+
+    Ok(args)
+}
+
+/// Counts the number of TeX engine runs reported in `latexmk` output.
+pub(crate) fn count_latexmk_passes(output: &[u8]) -> usize {
+    String::from_utf8_lossy(output)
+        .lines()
+        .filter(|line| line.contains("Run number"))
+        .filter(|line| {
+            [
+                "'latex'",
+                "'pdflatex'",
+                "'xelatex'",
+                "'lualatex'",
+                "'dvilualatex'",
+            ]
+            .iter()
+            .any(|rule| line.contains(rule))
+        })
+        .count()
+}
+
+/// Backend invoking the TeX engine directly.
+///
+/// The engine is rerun until the log no longer asks for it, up to a maximum number of passes.
+/// BibTeX or Biber is run after the first pass if the document has a bibliography; PostScript is
+/// converted from DVI using `dvips`. Unlike `latexmk`, passes are never skipped, even in a
+/// persistent build directory.
+#[derive(Clone, Debug)]
+pub struct DirectEngine {
+    /// Directory holding the executables, if not on the `PATH`.
+    bin_dir: Option<path::PathBuf>,
+    /// Maximum number of engine passes.
+    max_passes: usize,
+}
+
+impl DirectEngine {
+    /// Creates a new direct engine backend.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the directory holding the engine and tool executables.
+    ///
+    /// If not set, all executables are looked up on the current `PATH`.
+    pub fn bin_dir<P: Into<path::PathBuf>>(&mut self, bin_dir: P) -> &mut Self {
+        self.bin_dir = Some(bin_dir.into());
+        self
+    }
+
+    /// Sets the maximum number of engine passes.
+    ///
+    /// If not set, the engine is run at most five times. The limit includes the pass after the
+    /// bibliography tool, which is skipped if no pass is left.
+    pub fn max_passes(&mut self, max_passes: usize) -> &mut Self {
+        self.max_passes = max_passes.max(1);
+        self
+    }
+
+    /// Builds a command for the executable `name`.
+    fn command(&self, name: &str) -> process::Command {
+        match self.bin_dir {
+            Some(ref dir) => process::Command::new(dir.join(name)),
+            None => process::Command::new(name),
+        }
+    }
+}
+
+impl Default for DirectEngine {
+    fn default() -> Self {
+        DirectEngine {
+            bin_dir: None,
+            max_passes: 5,
+        }
+    }
+}
+
+impl RenderBackend for DirectEngine {
+    fn start(&self, job: &RenderJob<'_>) -> Result<Box<dyn BackendRun>, RenderingError> {
+        check_no_latexmkrc(job, "direct engine")?;
+
+        let (executable, format_args): (&str, &[&str]) = match (job.output_format, job.engine) {
+            (OutputFormat::Pdf, engine) => (engine.executable(), &[]),
+            (OutputFormat::PostScript, TexEngine::XeLatex) => return Err(unsupported_format(job)),
+            (_, TexEngine::PdfLatex) => ("latex", &[]),
+            (_, TexEngine::XeLatex) => ("xelatex", &["-no-pdf"]),
+            (_, TexEngine::LuaLatex) => ("dvilualatex", &[]),
+        };
+
+        let mut engine_args: Vec<OsString> = ENGINE_ARGS.iter().map(OsString::from).collect();
+        engine_args.extend(format_args.iter().map(OsString::from));
+        engine_args.push(job.shell_escape.latexmk_arg().into());
+        if job.synctex {
+            engine_args.push("-synctex=1".into());
+        }
+        engine_args.extend(job.args.iter().chain(job.engine_args).cloned());
+
+        match reproducible_pretex(job.engine).filter(|_| job.reproducible) {
+            Some(pretex) => {
+                // Pin the jobname, as the last argument is no longer just the main file.
+                engine_args.push(format!("-jobname={}", job.jobname).into());
+                let mut input = OsString::from(pretex);
+                input.push("\\input{");
+                input.push(job.main_file);
+                input.push("}");
+                engine_args.push(input);
+            }
+            None => engine_args.push(job.main_file.to_owned()),
+        }
+
+        let bibliography = job.bibliography.map(|backend| {
+            let mut cmd = self.command(backend.executable());
+            cmd.arg(job.jobname);
+            (backend, cmd)
+        });
+
+        let dvips = if job.output_format == OutputFormat::PostScript {
+            let mut cmd = self.command("dvips");
+            cmd.arg("-o")
+                .arg(job.output_file)
+                .arg(format!("{}.dvi", job.jobname));
+            Some(cmd)
+        } else {
+            None
+        };
+
+        Ok(Box::new(DirectEngineRun {
+            engine: self.command(executable).get_program().to_owned(),
+            engine_args,
+            bibliography,
+            dvips,
+            work_dir: job.work_dir.to_owned(),
+            jobname: job.jobname.to_owned(),
+            max_passes: self.max_passes,
+            passes: 0,
+            last: Step::Start,
+        }))
+    }
+
+    fn cache_id(&self) -> String {
+        format!("direct {:?} {}", self.bin_dir, self.max_passes)
+    }
+
+    fn programs(
+        &self,
+        engine: TexEngine,
+        output_format: OutputFormat,
+        bibliography: Option<BibBackend>,
+    ) -> Vec<path::PathBuf> {
+        tex_programs(engine, output_format, bibliography)
+            .into_iter()
+            .map(|name| match self.bin_dir {
+                Some(ref dir) => dir.join(name),
+                None => name.into(),
+            })
+            .collect()
+    }
+}
+
+/// Step of a direct engine run.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Step {
+    /// Nothing has run yet.
+    Start,
+    /// The engine ran.
+    Engine,
+    /// The bibliography tool ran.
+    Bibliography,
+    /// Post-processing ran, the rendering is complete.
+    Finished,
+}
+
+/// A rendering in progress using `DirectEngine`.
+#[derive(Debug)]
+struct DirectEngineRun {
+    /// Engine executable.
+    engine: OsString,
+    /// Arguments passed to the engine.
+    engine_args: Vec<OsString>,
+    /// Bibliography tool and its command, until it has run.
+    bibliography: Option<(BibBackend, process::Command)>,
+    /// Conversion to PostScript, if required.
+    dvips: Option<process::Command>,
+    /// Directory the rendering takes place in.
+    work_dir: path::PathBuf,
+    /// Jobname of the rendering.
+    jobname: String,
+    /// Maximum number of engine passes.
+    max_passes: usize,
+    /// Number of engine passes run.
+    passes: usize,
+    /// Last step run.
+    last: Step,
+}
+
+impl DirectEngineRun {
+    /// Reads a file produced by the engine, e.g. `log`.
+    fn read(&self, extension: &str) -> Vec<u8> {
+        fs::read(
+            self.work_dir
+                .join(format!("{}.{}", self.jobname, extension)),
+        )
+        .unwrap_or_default()
+    }
+
+    /// Checks whether the document requires running the bibliography tool.
+    fn needs_bibliography(&self, backend: BibBackend) -> bool {
+        match backend {
+            BibBackend::Bibtex => contains(&self.read("aux"), "\\bibdata"),
+            BibBackend::Biber => self.work_dir.join(format!("{}.bcf", self.jobname)).exists(),
+        }
+    }
+
+    /// Checks whether the log asks for another engine pass.
+    fn needs_rerun(&self) -> bool {
+        let log = self.read("log");
+        [
+            "Rerun to get",
+            "Label(s) may have changed",
+            "Please rerun LaTeX",
+            "Rerun LaTeX",
+        ]
+        .iter()
+        .any(|marker| contains(&log, marker))
+    }
+}
+
+impl BackendRun for DirectEngineRun {
+    fn next_command(
+        &mut self,
+        _previous: Option<&process::Output>,
+    ) -> Result<Option<process::Command>, RenderingError> {
+        let rerun = match self.last {
+            Step::Start => true,
+            Step::Bibliography => self.passes < self.max_passes,
+            Step::Engine => {
+                // The bibliography is useless without another engine pass to pick it up.
+                if self.passes < self.max_passes {
+                    if let Some((backend, cmd)) = self.bibliography.take() {
+                        if self.needs_bibliography(backend) {
+                            self.last = Step::Bibliography;
+                            return Ok(Some(cmd));
+                        }
+                    }
+                }
+                self.passes < self.max_passes && self.needs_rerun()
+            }
+            Step::Finished => return Ok(None),
+        };
+
+        if rerun {
+            self.passes += 1;
+            self.last = Step::Engine;
+            let mut cmd = process::Command::new(&self.engine);
+            cmd.args(&self.engine_args);
+            return Ok(Some(cmd));
+        }
+
+        self.last = Step::Finished;
+        Ok(self.dvips.take())
+    }
+
+    fn passes(&self) -> usize {
+        self.passes
+    }
+}
+
+/// Checks whether `haystack` contains `needle`.
+fn contains(haystack: &[u8], needle: &str) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle.as_bytes())
+}
+
+/// Backend running the Tectonic CLI.
+///
+/// Tectonic is based on XeTeX and fetches required packages on demand, it does not need a TeX
+/// installation. Only XeLaTeX is supported, producing PDF or extended DVI output. Bibliographies
+/// are processed with Tectonic's built-in BibTeX, Biber is not supported. Tectonic has no
+/// restricted shell escape mode, `ShellEscape::Restricted` disables shell escape.
+///
+/// Untrusted input is refused: Tectonic does not honor kpathsea's file access restrictions, so
+/// documents could read any file the process can.
+#[derive(Clone, Debug)]
+pub struct Tectonic {
+    /// Path to tectonic.
+    path: path::PathBuf,
+}
+
+impl Tectonic {
+    /// Creates a new Tectonic backend.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the path of `tectonic`.
+    ///
+    /// If not set, will look for `tectonic` on the current `PATH`.
+    pub fn path<P: Into<path::PathBuf>>(&mut self, path: P) -> &mut Self {
+        self.path = path.into();
+        self
+    }
+}
+
+impl Default for Tectonic {
+    fn default() -> Self {
+        Tectonic {
+            path: "tectonic".into(),
+        }
+    }
+}
+
+impl RenderBackend for Tectonic {
+    fn start(&self, job: &RenderJob<'_>) -> Result<Box<dyn BackendRun>, RenderingError> {
+        if job.engine != TexEngine::XeLatex {
+            return Err(RenderingError::UnsupportedEngine {
+                backend: "tectonic",
+                engine: job.engine,
+            });
+        }
+        check_no_latexmkrc(job, "tectonic")?;
+        if !job.engine_args.is_empty() {
+            return Err(RenderingError::InvalidOption(
+                "tectonic backend does not support engine arguments".to_owned(),
+            ));
+        }
+        // Tectonic ignores kpathsea's `openin_any` and `openout_any`, which untrusted input
+        // relies on.
+        if job.untrusted {
+            return Err(RenderingError::InvalidOption(
+                "tectonic backend does not support untrusted input".to_owned(),
+            ));
+        }
+        // BibTeX is built into Tectonic, Biber would have to be run separately.
+        if job.bibliography == Some(BibBackend::Biber) {
+            return Err(RenderingError::InvalidOption(
+                "tectonic backend does not support biber".to_owned(),
+            ));
+        }
+
+        let outfmt = match job.output_format {
+            OutputFormat::Pdf => "pdf",
+            OutputFormat::Dvi | OutputFormat::Svg => "xdv",
+            OutputFormat::PostScript => return Err(unsupported_format(job)),
+        };
+
+        let mut cmd = process::Command::new(&self.path);
+        cmd.args(["-X", "compile", "--keep-logs", "--outfmt", outfmt]);
+
+        if job.shell_escape == ShellEscape::Full {
+            cmd.args(["-Z", "shell-escape"]);
+        }
+
+        if job.reproducible {
+            cmd.args(["-Z", "deterministic-mode"]);
+        }
+
+        if job.synctex {
+            cmd.arg("--synctex");
+        }
+
+        cmd.args(job.args);
+
+        cmd.arg(job.main_file);
+
+        Ok(Box::new(SingleCommand {
+            command: Some(cmd),
+            passes: 0,
+            count_passes: count_tectonic_passes,
+        }))
+    }
+
+    fn cache_id(&self) -> String {
+        format!("tectonic {}", self.path.display())
+    }
+
+    fn programs(&self, _: TexEngine, _: OutputFormat, _: Option<BibBackend>) -> Vec<path::PathBuf> {
+        vec![self.path.clone()]
+    }
+}
+
+/// Counts the number of TeX engine runs reported in Tectonic output.
+fn count_tectonic_passes(output: &[u8]) -> usize {
+    String::from_utf8_lossy(output)
+        .lines()
+        .filter(|line| line.contains("Running TeX") || line.contains("Rerunning TeX"))
+        .count()
+}
+
+/// A rendering consisting of a single command.
+struct SingleCommand {
+    /// The command, until it has been handed out.
+    command: Option<process::Command>,
+    /// Number of engine passes run.
+    passes: usize,
+    /// Counts engine passes in the output of the command.
+    count_passes: fn(&[u8]) -> usize,
+}
+
+impl BackendRun for SingleCommand {
+    fn next_command(
+        &mut self,
+        previous: Option<&process::Output>,
+    ) -> Result<Option<process::Command>, RenderingError> {
+        if let Some(output) = previous {
+            self.passes +=
+                (self.count_passes)(&output.stdout) + (self.count_passes)(&output.stderr);
+        }
+
+        Ok(self.command.take())
+    }
+
+    fn passes(&self) -> usize {
+        self.passes
+    }
+}
+
+/// A minimal PDF document with a single, empty A4 page.
+const BLANK_PDF: &[u8] = b"%PDF-1.4\n\
+1 0 obj\n<< /Type /Catalog /Pages 2 0 R >>\nendobj\n\
+2 0 obj\n<< /Type /Pages /Kids [3 0 R] /Count 1 >>\nendobj\n\
+3 0 obj\n<< /Type /Page /Parent 2 0 R /MediaBox [0 0 595 842] >>\nendobj\n\
+xref\n0 4\n0000000000 65535 f \n0000000009 00000 n \n0000000058 00000 n \n0000000115 00000 n \n\
+trailer\n<< /Size 4 /Root 1 0 R >>\nstartxref\n186\n%%EOF\n";
+
+/// Backend producing canned results without running any tools.
+///
+/// Meant for testing code built on `TexRender` on machines without a TeX installation. Every
+/// rendering is recorded along with the sources, assets and options it received. Clones share
+/// their recordings, keep a clone to inspect them after handing the backend to
+/// `TexRender::backend`.
+///
+/// By default, renderings succeed with a blank single-page PDF, which is written in place of any
+/// output format.
+#[derive(Clone, Debug)]
+pub struct Fake {
+    /// Outcome of every rendering.
+    outcome: FakeOutcome,
+    /// LaTeX log to write, if not the default one.
+    log: Option<Vec<u8>>,
+    /// Renderings performed so far.
+    renderings: Arc<Mutex<Vec<FakeRendering>>>,
+}
+
+/// Canned outcome of a `Fake` rendering.
+#[derive(Clone, Debug)]
+enum FakeOutcome {
+    /// Successful rendering, with the contents of the output file.
+    Output(Vec<u8>),
+    /// Failed rendering, reported as a `RenderingError::LatexError`.
+    Failure {
+        /// Process exit code.
+        status: Option<i32>,
+        /// Content of stdout.
+        stdout: Vec<u8>,
+        /// Content of stderr.
+        stderr: Vec<u8>,
+    },
+}
+
+/// A rendering recorded by the `Fake` backend.
+#[derive(Clone, Debug)]
+pub struct FakeRendering {
+    /// Files inside the project directory, with paths relative to it, in order.
+    ///
+    /// Includes files left over from previous renderings in a persistent build directory.
+    pub sources: Vec<(path::PathBuf, Vec<u8>)>,
+    /// Assets, with paths relative to the assets directory, in order.
+    pub assets: Vec<(path::PathBuf, Vec<u8>)>,
+    /// Path of the main file, relative to the project directory.
+    pub main: path::PathBuf,
+    /// TeX engine selected.
+    pub engine: TexEngine,
+    /// Output format requested.
+    pub output_format: OutputFormat,
+    /// Shell escape policy applied.
+    pub shell_escape: ShellEscape,
+    /// Tool processing the bibliography, if any.
+    pub bibliography: Option<BibBackend>,
+    /// Whether the input was untrusted.
+    pub untrusted: bool,
+    /// Whether reproducible output was requested.
+    pub reproducible: bool,
+    /// Whether SyncTeX data was requested.
+    pub synctex: bool,
+    /// Additional arguments for the backend's main command.
+    pub args: Vec<OsString>,
+    /// Additional arguments for every engine pass.
+    pub engine_args: Vec<OsString>,
+    /// Contents of the additional `latexmkrc`, if any.
+    pub latexmkrc: Option<String>,
+}
+
+impl Fake {
+    /// Creates a new fake backend producing a blank single-page PDF.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes renderings succeed, producing `data` as the output file.
+    pub fn output(&mut self, data: Vec<u8>) -> &mut Self {
+        self.outcome = FakeOutcome::Output(data);
+        self
+    }
+
+    /// Makes renderings fail with a `RenderingError::LatexError`.
+    ///
+    /// The error's diagnostics are parsed from the log set through `log`, if any.
+    pub fn fail(&mut self, status: Option<i32>, stdout: Vec<u8>, stderr: Vec<u8>) -> &mut Self {
+        self.outcome = FakeOutcome::Failure {
+            status,
+            stdout,
+            stderr,
+        };
+        self
+    }
+
+    /// Sets the LaTeX log written by renderings.
+    ///
+    /// Warnings and the page count of a rendering are parsed from the log. If not set, successful
+    /// renderings write a log reporting a single page and failed ones write none.
+    pub fn log(&mut self, log: Vec<u8>) -> &mut Self {
+        self.log = Some(log);
+        self
+    }
+
+    /// Returns all renderings performed so far, in order.
+    pub fn renderings(&self) -> Vec<FakeRendering> {
+        self.renderings.lock().expect("lock poisoned").clone()
+    }
+
+    /// Records a rendering.
+    fn record(&self, job: &RenderJob<'_>) -> io::Result<()> {
+        let main = job
+            .work_dir
+            .strip_prefix(job.project_dir)
+            .unwrap_or(job.work_dir)
+            .join(job.main_file);
+
+        let rendering = FakeRendering {
+            sources: read_files(job.project_dir)?,
+            assets: match job.assets_dir {
+                Some(dir) => read_files(dir)?,
+                None => Vec::new(),
+            },
+            main,
+            engine: job.engine,
+            output_format: job.output_format,
+            shell_escape: job.shell_escape,
+            bibliography: job.bibliography,
+            untrusted: job.untrusted,
+            reproducible: job.reproducible,
+            synctex: job.synctex,
+            args: job.args.to_vec(),
+            engine_args: job.engine_args.to_vec(),
+            latexmkrc: job.latexmkrc.map(str::to_owned),
+        };
+
+        self.renderings
+            .lock()
+            .expect("lock poisoned")
+            .push(rendering);
+        Ok(())
+    }
+}
+
+impl Default for Fake {
+    fn default() -> Self {
+        Fake {
+            outcome: FakeOutcome::Output(BLANK_PDF.to_vec()),
+            log: None,
+            renderings: Arc::default(),
+        }
+    }
+}
+
+impl RenderBackend for Fake {
+    fn start(&self, job: &RenderJob<'_>) -> Result<Box<dyn BackendRun>, RenderingError> {
+        self.record(job).map_err(RenderingError::RunError)?;
+
+        let log_file = job.work_dir.join(format!("{}.log", job.jobname));
+        let write = |path: path::PathBuf, contents: &[u8]| {
+            fs::write(path, contents).map_err(RenderingError::RunError)
+        };
+
+        match self.outcome {
+            FakeOutcome::Output(ref data) => {
+                let output_file = if job.output_format == OutputFormat::Svg {
+                    format!("{}-1.svg", job.jobname)
+                } else {
+                    job.output_file.to_owned()
+                };
+                let log = self.log.clone().unwrap_or_else(|| {
+                    format!(
+                        "Output written on {} (1 page, {} bytes).\n",
+                        output_file,
+                        data.len()
+                    )
+                    .into_bytes()
+                });
+
+                write(job.work_dir.join(output_file), data)?;
+                write(log_file, &log)?;
+                Ok(Box::new(FakeRun { error: None }))
+            }
+            FakeOutcome::Failure {
+                status,
+                ref stdout,
+                ref stderr,
+            } => {
+                let log = self.log.clone().unwrap_or_default();
+                if self.log.is_some() {
+                    write(log_file, &log)?;
+                }
+
+                Ok(Box::new(FakeRun {
+                    error: Some(RenderingError::LatexError {
+                        status,
+                        stdout: stdout.clone(),
+                        stderr: stderr.clone(),
+                        diagnostics: diagnostics::parse_log(&log),
+                    }),
+                }))
+            }
+        }
+    }
+
+    fn cache_id(&self) -> String {
+        format!("fake {:?} {:?}", self.outcome, self.log)
+    }
+
+    fn produces_svg(&self) -> bool {
+        true
+    }
+}
+
+/// A rendering in progress using `Fake`.
+struct FakeRun {
+    /// Canned error to fail with, if any.
+    error: Option<RenderingError>,
+}
+
+impl BackendRun for FakeRun {
+    fn next_command(
+        &mut self,
+        _previous: Option<&process::Output>,
+    ) -> Result<Option<process::Command>, RenderingError> {
+        match self.error.take() {
+            Some(err) => Err(err),
+            None => Ok(None),
+        }
+    }
+
+    fn passes(&self) -> usize {
+        1
+    }
+}
+
+/// Reads all files below `dir`, with paths relative to it, sorted by path.
+fn read_files(dir: &path::Path) -> io::Result<Vec<(path::PathBuf, Vec<u8>)>> {
+    fn walk(
+        root: &path::Path,
+        dir: &path::Path,
+        files: &mut Vec<(path::PathBuf, Vec<u8>)>,
+    ) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                walk(root, &path, files)?;
+            } else {
+                let rel_path = path.strip_prefix(root).unwrap_or(&path).to_owned();
+                files.push((rel_path, fs::read(&path)?));
+            }
+        }
+        Ok(())
+    }
+
+    let mut files = Vec::new();
+    walk(dir, dir, &mut files)?;
+    files.sort();
+    Ok(files)
+}
+
+/// Refuses jobs with a `latexmkrc` in backends other than `latexmk`.
+fn check_no_latexmkrc(job: &RenderJob<'_>, backend: &str) -> Result<(), RenderingError> {
+    if job.latexmkrc.is_none() {
+        return Ok(());
+    }
+
+    Err(RenderingError::InvalidOption(format!(
+        "{} backend does not support latexmkrc",
+        backend
+    )))
+}
+
+/// Returns the error for a job asking for an unsupported output format.
+fn unsupported_format(job: &RenderJob<'_>) -> RenderingError {
+    RenderingError::UnsupportedOutputFormat {
+        engine: job.engine,
+        format: job.output_format,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        count_latexmk_passes, BackendRun, DirectEngine, RenderBackend, RenderJob, Tectonic,
+    };
+    use crate::{BibBackend, OutputFormat, RenderingError, ShellEscape, TexEngine};
+    use std::{ffi::OsStr, fs, process};
+
+    fn job<'a>(work_dir: &'a std::path::Path) -> RenderJob<'a> {
+        RenderJob {
+            project_dir: work_dir,
+            work_dir,
+            assets_dir: None,
+            main_file: OsStr::new("input.tex"),
+            jobname: "input",
+            output_file: "input.pdf",
+            engine: TexEngine::PdfLatex,
+            output_format: OutputFormat::Pdf,
+            shell_escape: ShellEscape::Disabled,
+            bibliography: None,
+            untrusted: false,
+            reproducible: false,
+            synctex: false,
+            args: &[],
+            engine_args: &[],
+            latexmkrc: None,
+        }
+    }
+
+    fn program(cmd: &process::Command) -> String {
+        cmd.get_program().to_string_lossy().into_owned()
+    }
+
+    fn next(run: &mut Box<dyn BackendRun>) -> Option<String> {
+        run.next_command(None).unwrap().as_ref().map(program)
+    }
+
+    #[test]
+    fn counts_engine_passes() {
+        let stderr = b"Rc files read:\n  NONE\n\
+Latexmk: This is Latexmk, John Collins, 17 Mar. 2022. Version 4.77, version: 4.77.\n\
+Latexmk: applying rule 'pdflatex'...\n\
+Rule 'pdflatex':  File changes, etc:\n\
+Run number 1 of rule 'pdflatex'\n\
+Run number 1 of rule 'bibtex input'\n\
+Run number 2 of rule 'pdflatex'\n";
+        assert_eq!(count_latexmk_passes(stderr), 2);
+    }
+
+    #[test]
+    fn counts_dvilualatex_passes() {
+        let stderr = b"Latexmk: applying rule 'dvilualatex'...\n\
+Rule 'dvilualatex':  File changes, etc:\n\
+Run number 1 of rule 'dvilualatex'\n\
+Run number 1 of rule 'dvips'\n";
+        assert_eq!(count_latexmk_passes(stderr), 1);
+    }
+
+    #[test]
+    fn direct_engine_reruns_as_requested() {
+        let dir = tempdir::TempDir::new("texrender-test").unwrap();
+        let mut job = job(dir.path());
+        job.bibliography = Some(BibBackend::Bibtex);
+        job.output_format = OutputFormat::PostScript;
+        job.output_file = "input.ps";
+
+        let mut run = DirectEngine::new().start(&job).unwrap();
+        assert_eq!(next(&mut run).as_deref(), Some("latex"));
+
+        fs::write(dir.path().join("input.aux"), r"\bibdata{refs}").unwrap();
+        fs::write(
+            dir.path().join("input.log"),
+            "Rerun to get citations correct.",
+        )
+        .unwrap();
+        assert_eq!(next(&mut run).as_deref(), Some("bibtex"));
+        assert_eq!(next(&mut run).as_deref(), Some("latex"));
+        assert_eq!(next(&mut run).as_deref(), Some("latex"));
+
+        fs::write(dir.path().join("input.log"), "Output written on input.dvi.").unwrap();
+        assert_eq!(next(&mut run).as_deref(), Some("dvips"));
+        assert_eq!(next(&mut run), None);
+        assert_eq!(run.passes(), 3);
+    }
+
+    #[test]
+    fn direct_engine_limits_passes() {
+        let dir = tempdir::TempDir::new("texrender-test").unwrap();
+        fs::write(dir.path().join("input.log"), "Rerun to get outlines right").unwrap();
+
+        let mut run = DirectEngine::new()
+            .max_passes(2)
+            .start(&job(dir.path()))
+            .unwrap();
+        assert_eq!(next(&mut run).as_deref(), Some("pdflatex"));
+        assert_eq!(next(&mut run).as_deref(), Some("pdflatex"));
+        assert_eq!(next(&mut run), None);
+    }
+
+    #[test]
+    fn direct_engine_limits_passes_with_bibliography() {
+        let dir = tempdir::TempDir::new("texrender-test").unwrap();
+        fs::write(dir.path().join("input.aux"), r"\bibdata{refs}").unwrap();
+        let mut job = job(dir.path());
+        job.bibliography = Some(BibBackend::Bibtex);
+
+        let mut run = DirectEngine::new().max_passes(1).start(&job).unwrap();
+        assert_eq!(next(&mut run).as_deref(), Some("pdflatex"));
+        assert_eq!(next(&mut run), None);
+        assert_eq!(run.passes(), 1);
+
+        let mut run = DirectEngine::new().max_passes(2).start(&job).unwrap();
+        assert_eq!(next(&mut run).as_deref(), Some("pdflatex"));
+        assert_eq!(next(&mut run).as_deref(), Some("bibtex"));
+        assert_eq!(next(&mut run).as_deref(), Some("pdflatex"));
+        assert_eq!(next(&mut run), None);
+        assert_eq!(run.passes(), 2);
+    }
+
+    #[test]
+    fn tectonic_supports_xelatex_only() {
+        let dir = tempdir::TempDir::new("texrender-test").unwrap();
+        let mut job = job(dir.path());
+
+        match Tectonic::new().start(&job) {
+            Err(RenderingError::UnsupportedEngine { .. }) => (),
+            Err(err) => panic!("expected unsupported engine, got {:?}", err),
+            Ok(_) => panic!("expected unsupported engine"),
+        }
+
+        job.engine = TexEngine::XeLatex;
+        job.shell_escape = ShellEscape::Full;
+        let mut run = Tectonic::new().start(&job).unwrap();
+        let cmd = run.next_command(None).unwrap().unwrap();
+        let args: Vec<_> = cmd.get_args().collect();
+        assert_eq!(
+            args,
+            [
+                "-X",
+                "compile",
+                "--keep-logs",
+                "--outfmt",
+                "pdf",
+                "-Z",
+                "shell-escape",
+                "input.tex"
+            ]
+        );
+    }
+
+    #[test]
+    fn tectonic_rejects_biber() {
+        let dir = tempdir::TempDir::new("texrender-test").unwrap();
+        let mut job = job(dir.path());
+        job.engine = TexEngine::XeLatex;
+        job.bibliography = Some(BibBackend::Biber);
+
+        match Tectonic::new().start(&job) {
+            Err(RenderingError::InvalidOption(_)) => (),
+            Err(err) => panic!("expected invalid option, got {:?}", err),
+            Ok(_) => panic!("expected invalid option"),
+        }
+
+        job.bibliography = Some(BibBackend::Bibtex);
+        assert!(Tectonic::new().start(&job).is_ok());
+    }
+
+    #[test]
+    fn tectonic_rejects_untrusted_input() {
+        let dir = tempdir::TempDir::new("texrender-test").unwrap();
+        let mut job = job(dir.path());
+        job.engine = TexEngine::XeLatex;
+        job.untrusted = true;
+
+        match Tectonic::new().start(&job) {
+            Err(RenderingError::InvalidOption(_)) => (),
+            Err(err) => panic!("expected invalid option, got {:?}", err),
+            Ok(_) => panic!("expected invalid option"),
+        }
+    }
+}
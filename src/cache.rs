@@ -0,0 +1,488 @@
+//! Content-addressed render cache.
+//!
+//! A `RenderCache` stores rendering results on disk, keyed by a hash of everything that goes into
+//! a rendering: the source, all assets, the files in every search path folder, the environment
+//! and the engine options. Attach a cache to a `TexRender` using `TexRender::cache`; identical
+//! renderings are then served from the cache without invoking `latexmk`.
+//!
+//! The cache is bounded in size. Once the limit is exceeded, least recently used entries are
+//! evicted.
+
+use sha2::{Digest, Sha256};
+use std::{
+    fs, io, path, process,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Filename of the LaTeX log inside a cache entry.
+///
+/// Its modification time records the last use of the entry.
+const LOG_FILE: &str = "output.log";
+
+/// Filename of the list of output files inside a cache entry.
+const MANIFEST_FILE: &str = "manifest";
+
+/// Directory holding the output files inside a cache entry.
+const FILES_DIR: &str = "files";
+
+/// Filename of the list of artifacts inside a cache entry.
+const ARTIFACTS_MANIFEST_FILE: &str = "artifacts";
+
+/// Directory holding the artifacts inside a cache entry.
+const ARTIFACTS_DIR: &str = "artifact-files";
+
+/// On-disk cache of rendering results.
+#[derive(Clone, Debug)]
+pub struct RenderCache {
+    /// Directory holding the cache entries.
+    dir: path::PathBuf,
+    /// Maximum total size of all entries in bytes.
+    max_size: u64,
+}
+
+/// A cached rendering.
+#[derive(Debug)]
+pub(crate) struct CacheHit {
+    /// Names of the output files along with open handles, in order.
+    pub(crate) files: Vec<(String, fs::File)>,
+    /// Names of the artifacts along with open handles, in order.
+    pub(crate) artifacts: Vec<(String, fs::File)>,
+    /// Contents of the LaTeX log.
+    pub(crate) log: Vec<u8>,
+}
+
+/// Information about a single cache entry.
+#[derive(Clone, Debug)]
+pub struct CacheEntry {
+    /// Cache key, a hex-encoded SHA-256 hash.
+    pub key: String,
+    /// Total size of the entry in bytes.
+    pub size: u64,
+    /// Time the entry was last written or read.
+    pub last_used: SystemTime,
+}
+
+impl RenderCache {
+    /// Opens a cache in the given directory, creating it if it does not exist.
+    ///
+    /// `max_size` is the maximum total size of all entries in bytes.
+    pub fn open<P: Into<path::PathBuf>>(dir: P, max_size: u64) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        Ok(RenderCache { dir, max_size })
+    }
+
+    /// Returns the directory of the cache.
+    pub fn dir(&self) -> &path::Path {
+        &self.dir
+    }
+
+    /// Returns the maximum size of the cache in bytes.
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    /// Lists all entries, least recently used first.
+    pub fn entries(&self) -> io::Result<Vec<CacheEntry>> {
+        let mut entries = Vec::new();
+
+        for dir_entry in fs::read_dir(&self.dir)? {
+            let dir_entry = dir_entry?;
+            let key = match dir_entry.file_name().into_string() {
+                Ok(key) if is_key(&key) => key,
+                _ => continue,
+            };
+
+            let path = dir_entry.path();
+            let info = dir_size(&path)
+                .and_then(|size| Ok((size, fs::metadata(path.join(LOG_FILE))?.modified()?)));
+            // Entries may be evicted concurrently by another renderer sharing the cache.
+            let (size, last_used) = match info {
+                Ok(info) => info,
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+
+            entries.push(CacheEntry {
+                key,
+                size,
+                last_used,
+            });
+        }
+
+        entries.sort_by_key(|entry| entry.last_used);
+        Ok(entries)
+    }
+
+    /// Returns the total size of all entries in bytes.
+    pub fn size(&self) -> io::Result<u64> {
+        Ok(self.entries()?.iter().map(|entry| entry.size).sum())
+    }
+
+    /// Removes a single entry.
+    ///
+    /// Removing a non-existent entry is not an error.
+    pub fn remove(&self, key: &str) -> io::Result<()> {
+        if !is_key(key) {
+            return Ok(());
+        }
+
+        match fs::remove_dir_all(self.dir.join(key)) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            other => other,
+        }
+    }
+
+    /// Removes all entries.
+    pub fn clear(&self) -> io::Result<()> {
+        for entry in self.entries()? {
+            self.remove(&entry.key)?;
+        }
+        Ok(())
+    }
+
+    /// Looks up a cached rendering, marking it as recently used.
+    ///
+    /// The output files of the entry are opened right away, so they remain readable even if the
+    /// entry is evicted in the meantime. Unreadable entries are treated as missing.
+    pub(crate) fn lookup(&self, key: &str) -> Option<CacheHit> {
+        let entry_dir = self.dir.join(key);
+        let log_file = entry_dir.join(LOG_FILE);
+
+        let log = fs::read(&log_file).ok()?;
+        let files = open_files(&entry_dir, MANIFEST_FILE, FILES_DIR).ok()?;
+        let artifacts = open_files(&entry_dir, ARTIFACTS_MANIFEST_FILE, ARTIFACTS_DIR).ok()?;
+
+        // Failing to update the timestamp only affects eviction order.
+        let _ = fs::File::options()
+            .write(true)
+            .open(&log_file)
+            .and_then(|file| file.set_modified(SystemTime::now()));
+
+        Some(CacheHit {
+            files,
+            artifacts,
+            log,
+        })
+    }
+
+    /// Stores a rendering, evicting old entries if the cache grows too large.
+    ///
+    /// `files` and `artifacts` are the names and current locations of the output files and
+    /// artifacts, in order.
+    pub(crate) fn insert(
+        &self,
+        key: &str,
+        files: &[(String, path::PathBuf)],
+        artifacts: &[(String, path::PathBuf)],
+        log: &[u8],
+    ) -> io::Result<()> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or_default();
+        let staging = self
+            .dir
+            .join(format!(".{}-{}-{}", key, process::id(), nanos));
+
+        fs::create_dir(&staging)?;
+        store_files(&staging, MANIFEST_FILE, FILES_DIR, files)?;
+        store_files(&staging, ARTIFACTS_MANIFEST_FILE, ARTIFACTS_DIR, artifacts)?;
+        fs::write(staging.join(LOG_FILE), log)?;
+
+        // Entries appear atomically. If another rendering stored the same entry in the meantime,
+        // its (identical) result is kept.
+        if fs::rename(&staging, self.dir.join(key)).is_err() {
+            fs::remove_dir_all(&staging)?;
+        }
+
+        self.evict()
+    }
+
+    /// Evicts least recently used entries until the cache fits its size limit.
+    ///
+    /// Entries already evicted by another renderer sharing the cache are skipped.
+    fn evict(&self) -> io::Result<()> {
+        let entries = self.entries()?;
+        let mut size: u64 = entries.iter().map(|entry| entry.size).sum();
+
+        for entry in entries {
+            if size <= self.max_size {
+                break;
+            }
+            self.remove(&entry.key)?;
+            size -= entry.size;
+        }
+
+        Ok(())
+    }
+}
+
+/// Copies files into `dir_name` inside a cache entry, listing them in `manifest_name`.
+fn store_files(
+    entry_dir: &path::Path,
+    manifest_name: &str,
+    dir_name: &str,
+    files: &[(String, path::PathBuf)],
+) -> io::Result<()> {
+    fs::create_dir(entry_dir.join(dir_name))?;
+
+    let mut manifest = String::new();
+    for (name, path) in files {
+        fs::copy(path, entry_dir.join(dir_name).join(name))?;
+        manifest.push_str(name);
+        manifest.push('\n');
+    }
+    fs::write(entry_dir.join(manifest_name), manifest)
+}
+
+/// Opens the files listed in `manifest_name` of a cache entry.
+fn open_files(
+    entry_dir: &path::Path,
+    manifest_name: &str,
+    dir_name: &str,
+) -> io::Result<Vec<(String, fs::File)>> {
+    let manifest = fs::read_to_string(entry_dir.join(manifest_name))?;
+
+    let mut files = Vec::new();
+    for name in manifest.lines() {
+        let file = fs::File::open(entry_dir.join(dir_name).join(name))?;
+        files.push((name.to_owned(), file));
+    }
+    Ok(files)
+}
+
+/// Computes the total size of all files below `dir`.
+fn dir_size(dir: &path::Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+    Ok(size)
+}
+
+/// Checks whether a filename is a valid cache key.
+fn is_key(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Incremental builder for cache keys.
+#[derive(Debug, Default)]
+pub(crate) struct KeyBuilder(Sha256);
+
+impl KeyBuilder {
+    /// Creates a new key builder.
+    pub(crate) fn new() -> Self {
+        let mut builder = KeyBuilder::default();
+        builder.bytes(b"texrender-cache-v1");
+        builder
+    }
+
+    /// Adds a length-prefixed byte string to the key.
+    pub(crate) fn bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        self.0.update((bytes.len() as u64).to_le_bytes());
+        self.0.update(bytes);
+        self
+    }
+
+    /// Adds the contents of a directory to the key.
+    ///
+    /// All files are hashed recursively, along with their paths relative to `dir`. The location
+    /// of `dir` itself does not influence the key. Missing directories are hashed as empty.
+    pub(crate) fn dir(&mut self, dir: &path::Path) -> io::Result<&mut Self> {
+        let mut files = Vec::new();
+        if dir.is_dir() {
+            collect_files(dir, dir, &mut files)?;
+        }
+        files.sort();
+
+        self.bytes(&(files.len() as u64).to_le_bytes());
+        for rel_path in files {
+            self.bytes(rel_path.to_string_lossy().as_bytes());
+            self.bytes(&fs::read(dir.join(&rel_path))?);
+        }
+
+        Ok(self)
+    }
+
+    /// Adds the files below a directory to the key, without reading them.
+    ///
+    /// The paths of all files relative to `dir` are hashed recursively, along with their sizes and
+    /// modification times and the location of `dir`. Missing directories are hashed as empty.
+    pub(crate) fn dir_metadata(&mut self, dir: &path::Path) -> io::Result<&mut Self> {
+        let mut files = Vec::new();
+        if dir.is_dir() {
+            collect_files(dir, dir, &mut files)?;
+        }
+        files.sort();
+
+        self.bytes(dir.to_string_lossy().as_bytes())
+            .bytes(&(files.len() as u64).to_le_bytes());
+        for rel_path in files {
+            let metadata = fs::metadata(dir.join(&rel_path))?;
+            let modified = metadata
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            self.bytes(rel_path.to_string_lossy().as_bytes())
+                .bytes(&metadata.len().to_le_bytes())
+                .bytes(&modified.as_nanos().to_le_bytes());
+        }
+
+        Ok(self)
+    }
+
+    /// Finishes the key, returning it hex-encoded.
+    pub(crate) fn finish(self) -> String {
+        self.0
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
+
+/// Recursively collects the paths of all files below `dir`, relative to `base`.
+fn collect_files(
+    base: &path::Path,
+    dir: &path::Path,
+    files: &mut Vec<path::PathBuf>,
+) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(base, &path, files)?;
+        } else {
+            files.push(
+                path.strip_prefix(base)
+                    .expect("file outside of base dir")
+                    .to_owned(),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KeyBuilder, RenderCache};
+    use std::{fs, io::Read, path};
+
+    fn output(dir: &path::Path, fill: u8) -> Vec<(String, path::PathBuf)> {
+        let path = dir.join(format!("output-{}.pdf", fill));
+        fs::write(&path, [fill; 1000]).unwrap();
+        vec![("input.pdf".to_owned(), path)]
+    }
+
+    fn key(n: u8) -> String {
+        let mut builder = KeyBuilder::new();
+        builder.bytes(&[n]);
+        builder.finish()
+    }
+
+    #[test]
+    fn stores_and_evicts_entries() {
+        let dir = tempdir::TempDir::new("texrender-cache-test").unwrap();
+        let src = tempdir::TempDir::new("texrender-cache-test").unwrap();
+        let cache = RenderCache::open(dir.path(), 2500).unwrap();
+        let log = b"Output written on input.pdf (2 pages, 1000 bytes).\n";
+
+        let aux = src.path().join("input.aux");
+        fs::write(&aux, b"\\relax").unwrap();
+        let artifacts = [("input.aux".to_owned(), aux)];
+        cache
+            .insert(&key(1), &output(src.path(), 1), &artifacts, log)
+            .unwrap();
+        cache
+            .insert(&key(2), &output(src.path(), 2), &[], log)
+            .unwrap();
+        assert_eq!(cache.entries().unwrap().len(), 2);
+
+        // Reading entry 1 leaves entry 2 as the least recently used one, which gets evicted.
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        let hit = cache.lookup(&key(1)).unwrap();
+        assert_eq!(hit.log, log);
+        assert_eq!(hit.files[0].0, "input.pdf");
+        let mut data = Vec::new();
+        (&hit.files[0].1).read_to_end(&mut data).unwrap();
+        assert_eq!(data, [1; 1000]);
+        assert_eq!(hit.artifacts[0].0, "input.aux");
+
+        cache
+            .insert(&key(3), &output(src.path(), 3), &[], log)
+            .unwrap();
+        assert!(cache.lookup(&key(2)).is_none());
+        assert!(cache.lookup(&key(3)).is_some());
+        assert!(cache.size().unwrap() <= 2500);
+
+        cache.clear().unwrap();
+        assert!(cache.entries().unwrap().is_empty());
+    }
+
+    #[test]
+    fn skips_entries_being_evicted() {
+        let dir = tempdir::TempDir::new("texrender-cache-test").unwrap();
+        let src = tempdir::TempDir::new("texrender-cache-test").unwrap();
+        let cache = RenderCache::open(dir.path(), 1500).unwrap();
+        let log = b"Output written on input.pdf (1 page, 1000 bytes).\n";
+
+        // Another renderer removing an entry leaves it without its log for a moment.
+        fs::create_dir_all(dir.path().join(key(1)).join("files")).unwrap();
+        assert!(cache.entries().unwrap().is_empty());
+
+        cache
+            .insert(&key(2), &output(src.path(), 2), &[], log)
+            .unwrap();
+        assert_eq!(cache.entries().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn directory_keys_ignore_location() {
+        let a = tempdir::TempDir::new("texrender-cache-test").unwrap();
+        let b = tempdir::TempDir::new("texrender-cache-test").unwrap();
+        fs::write(a.path().join("logo.png"), b"png").unwrap();
+        fs::write(b.path().join("logo.png"), b"png").unwrap();
+
+        let hash = |path| {
+            let mut builder = KeyBuilder::new();
+            builder.dir(path).unwrap();
+            builder.finish()
+        };
+        assert_eq!(hash(a.path()), hash(b.path()));
+
+        fs::write(b.path().join("logo.png"), b"gif").unwrap();
+        assert_ne!(hash(a.path()), hash(b.path()));
+    }
+
+    #[test]
+    fn directory_metadata_keys_track_files() {
+        let a = tempdir::TempDir::new("texrender-cache-test").unwrap();
+        let b = tempdir::TempDir::new("texrender-cache-test").unwrap();
+        fs::create_dir(a.path().join("tex")).unwrap();
+        fs::write(a.path().join("tex").join("house.sty"), b"sty").unwrap();
+
+        let hash = |path| {
+            let mut builder = KeyBuilder::new();
+            builder.dir_metadata(path).unwrap();
+            builder.finish()
+        };
+        let initial = hash(a.path());
+        assert_eq!(initial, hash(a.path()));
+        assert_ne!(initial, hash(b.path()));
+
+        fs::write(a.path().join("tex").join("house.sty"), b"style").unwrap();
+        let changed = hash(a.path());
+        assert_ne!(initial, changed);
+
+        fs::write(a.path().join("house.cls"), b"cls").unwrap();
+        assert_ne!(changed, hash(a.path()));
+    }
+}
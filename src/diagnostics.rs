@@ -0,0 +1,535 @@
+//! LaTeX log parsing.
+//!
+//! Extracts errors and warnings from the `.log` file a TeX engine writes alongside its output. The
+//! parser is heuristic: TeX logs have no formal grammar, so only the common message formats of
+//! TeX, LaTeX and its packages are recognized.
+
+use std::{fmt, path};
+
+/// Width at which TeX hard-wraps log lines (`max_print_line`).
+const MAX_PRINT_LINE: usize = 79;
+
+/// Maximum number of lines following an error that are searched for context.
+const MAX_CONTEXT_LINES: usize = 12;
+
+/// Severity of a diagnostic.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Severity {
+    /// An error that caused (or would have caused) the run to fail.
+    Error,
+    /// A warning, the document was still rendered.
+    Warning,
+}
+
+/// A single error or warning found in a LaTeX log.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Diagnostic {
+    /// Source file the message refers to, if known.
+    pub file: Option<path::PathBuf>,
+    /// Line inside `file` the message refers to, if known.
+    pub line: Option<u32>,
+    /// Severity of the message.
+    pub severity: Severity,
+    /// The message itself, with continuation lines joined.
+    pub message: String,
+    /// Context lines printed by TeX after the message, e.g. the offending input line.
+    pub context: Vec<String>,
+}
+
+impl Diagnostic {
+    /// Returns the citation key if the diagnostic reports an undefined citation.
+    pub fn undefined_citation(&self) -> Option<&str> {
+        let rest = self.message.strip_prefix("LaTeX Warning: Citation ")?;
+        if !rest.contains(" undefined") {
+            return None;
+        }
+
+        // LaTeX quotes keys as `key', biblatex as 'key'.
+        let rest = rest.strip_prefix(|c| c == '`' || c == '\'')?;
+        Some(&rest[..rest.find('\'')?])
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(ref file) = self.file {
+            write!(f, "{}:", file.display())?;
+        }
+        if let Some(line) = self.line {
+            write!(f, "{}:", line)?;
+        }
+        if self.file.is_some() || self.line.is_some() {
+            f.write_str(" ")?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+/// Parses the contents of a LaTeX log file into a list of diagnostics.
+///
+/// Diagnostics are returned in the order they appear in the log. Logs are expected to be written
+/// with `-file-line-error`, although `!`-style errors are recognized as well.
+pub fn parse_log(log: &[u8]) -> Vec<Diagnostic> {
+    let lines = unwrap_lines(&String::from_utf8_lossy(log));
+    let mut diagnostics = Vec::new();
+    let mut files = FileStack::default();
+
+    let mut idx = 0;
+    while idx < lines.len() {
+        let line = &lines[idx];
+        idx += 1;
+
+        if let Some((file, line_no, message)) = split_file_line_error(line) {
+            let (context, consumed) = error_context(&lines[idx..]);
+            idx += consumed;
+            diagnostics.push(Diagnostic {
+                file: Some(file.into()),
+                line: Some(line_no),
+                severity: Severity::Error,
+                message: message.to_owned(),
+                context,
+            });
+        } else if let Some(message) = line.strip_prefix("! ") {
+            let (context, consumed) = error_context(&lines[idx..]);
+            idx += consumed;
+            let line_no = context.iter().find_map(|l| context_line_number(l));
+            diagnostics.push(Diagnostic {
+                file: files.current(),
+                line: line_no,
+                severity: Severity::Error,
+                message: message.to_owned(),
+                context,
+            });
+        } else if let Some(prefix) = warning_prefix(line) {
+            let mut message = line.trim_end().to_owned();
+            while idx < lines.len() && is_continuation(&lines[idx], &prefix) {
+                message.push(' ');
+                message.push_str(strip_continuation(&lines[idx], &prefix));
+                idx += 1;
+            }
+            let line_no = warning_line_number(&message);
+            diagnostics.push(Diagnostic {
+                file: files.current(),
+                line: line_no,
+                severity: Severity::Warning,
+                message,
+                context: Vec::new(),
+            });
+        } else if line.starts_with("Overfull \\")
+            || line.starts_with("Underfull \\")
+            || line.starts_with("Missing character: ")
+        {
+            let mut context = Vec::new();
+            if line.contains(" in paragraph at lines ") || line.contains(" detected at line ") {
+                if let Some(next) = lines.get(idx).filter(|l| !l.trim().is_empty()) {
+                    context.push(next.clone());
+                    idx += 1;
+                }
+            }
+            diagnostics.push(Diagnostic {
+                file: files.current(),
+                line: warning_line_number(line),
+                severity: Severity::Warning,
+                message: line.trim_end().to_owned(),
+                context,
+            });
+        } else {
+            files.scan(line);
+        }
+    }
+
+    diagnostics
+}
+
+/// Extracts the number of pages written from a LaTeX log file.
+///
+/// Looks for the final `Output written on <file> (<n> pages, <m> bytes).` line.
+pub fn page_count(log: &[u8]) -> Option<u32> {
+    unwrap_lines(&String::from_utf8_lossy(log))
+        .iter()
+        .rev()
+        .find_map(|line| {
+            let rest = &line[line.find("Output written on ")?..];
+            let count = &rest[rest.find(" (")? + 2..];
+            let digits = count.bytes().take_while(u8::is_ascii_digit).count();
+            count[..digits].parse().ok()
+        })
+}
+
+/// Extracts error messages from a BibTeX or Biber log (`.blg`) file.
+///
+/// Returns an empty list if the tool reported no errors.
+pub fn bibliography_errors(blg: &[u8]) -> Vec<String> {
+    let blg = String::from_utf8_lossy(blg);
+    let lines: Vec<&str> = blg.lines().collect();
+    let mut errors = Vec::new();
+
+    for (idx, line) in lines.iter().enumerate() {
+        if let Some(pos) = line.find("ERROR - ") {
+            // Biber: `[123] Utils.pm:409> ERROR - Cannot find 'missing.bib'!`
+            errors.push(line[pos + 8..].trim().to_owned());
+        } else if lines
+            .get(idx + 1)
+            .is_some_and(|next| next.starts_with("---line "))
+            || line.starts_with("I couldn't open ")
+            || line.starts_with("I found no ")
+        {
+            // BibTeX: the message is followed by the location, e.g. `---line 3 of file input.aux`.
+            errors.push(line.trim().to_owned());
+        } else if !line.starts_with("---")
+            && (line.contains("---line ") || line.contains("---while reading file "))
+        {
+            // BibTeX: the location shares the line with the message, as for `.bib` syntax errors.
+            errors.push(line.trim().to_owned());
+        }
+    }
+
+    errors
+}
+
+/// Joins lines hard-wrapped by TeX at `MAX_PRINT_LINE` characters.
+fn unwrap_lines(log: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut current = String::new();
+
+    for line in log.lines() {
+        current.push_str(line);
+        if line.chars().count() != MAX_PRINT_LINE {
+            lines.push(std::mem::take(&mut current));
+        }
+    }
+
+    if !current.is_empty() {
+        lines.push(current);
+    }
+
+    lines
+}
+
+/// Splits a `file:line: message` error, as produced by `-file-line-error`.
+fn split_file_line_error(line: &str) -> Option<(&str, u32, &str)> {
+    let mut search_start = 0;
+    while let Some(pos) = line[search_start..].find(':') {
+        let colon = search_start + pos;
+        let rest = &line[colon + 1..];
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+
+        if colon > 0 && digits > 0 && rest[digits..].starts_with(": ") {
+            let file = &line[..colon];
+            // Guard against messages that merely contain a `:<digits>: ` sequence.
+            if file.contains(' ') && !file.starts_with('.') && !file.starts_with('/') {
+                return None;
+            }
+            let line_no = rest[..digits].parse().ok()?;
+            return Some((file, line_no, &rest[digits + 2..]));
+        }
+
+        search_start = colon + 1;
+    }
+
+    None
+}
+
+/// Collects context lines following an error message.
+///
+/// Returns the context and the number of lines consumed. The context is the `l.<line>` line
+/// showing the offending input and its continuation; if none is found, no context is returned.
+fn error_context(lines: &[String]) -> (Vec<String>, usize) {
+    let limit = lines.len().min(MAX_CONTEXT_LINES);
+
+    for idx in 0..limit {
+        if context_line_number(&lines[idx]).is_some() {
+            let mut end = idx + 1;
+            if lines.get(end).is_some_and(|l| !l.trim().is_empty()) {
+                end += 1;
+            }
+
+            let context = lines[idx..end]
+                .iter()
+                .filter(|l| !l.trim().is_empty())
+                .cloned()
+                .collect();
+            return (context, end);
+        }
+    }
+
+    (Vec::new(), 0)
+}
+
+/// Extracts the line number from an `l.<line> ...` context line.
+fn context_line_number(line: &str) -> Option<u32> {
+    let rest = line.strip_prefix("l.")?;
+    let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+    rest[..digits].parse().ok()
+}
+
+/// Returns the continuation prefix if the line starts a warning.
+///
+/// For package and class warnings this is the parenthesized name TeX puts in front of
+/// continuation lines, e.g. `(hyperref)`. Engine warnings are never continued, their prefix is
+/// empty.
+pub(crate) fn warning_prefix(line: &str) -> Option<String> {
+    if line.starts_with("LaTeX Warning: ") {
+        // LaTeX indents continuation lines of its own warnings with spaces.
+        return Some(" ".to_owned());
+    }
+
+    if line.starts_with("LaTeX Font Warning: ") {
+        return Some("(Font)".to_owned());
+    }
+
+    for kind in &["Package ", "Class "] {
+        if let Some(rest) = line.strip_prefix(kind) {
+            if let Some(pos) = rest.find(" Warning: ") {
+                let name = &rest[..pos];
+                if !name.contains(' ') {
+                    return Some(format!("({})", name));
+                }
+            }
+        }
+    }
+
+    if line.starts_with("pdfTeX warning") || line.starts_with("LuaTeX warning") {
+        return Some(String::new());
+    }
+
+    None
+}
+
+/// Checks whether a line continues a multi-line warning.
+pub(crate) fn is_continuation(line: &str, prefix: &str) -> bool {
+    !prefix.is_empty() && line.starts_with(prefix) && !line.trim().is_empty()
+}
+
+/// Strips the continuation prefix and surrounding whitespace from a line.
+pub(crate) fn strip_continuation<'a>(line: &'a str, prefix: &str) -> &'a str {
+    line[prefix.len()..].trim()
+}
+
+/// Extracts a line number from a warning message.
+///
+/// Recognizes `on input line <n>`, `at lines <n>--<m>` and `at line <n>`.
+fn warning_line_number(message: &str) -> Option<u32> {
+    for marker in &["on input line ", "at lines ", "at line "] {
+        if let Some(pos) = message.find(marker) {
+            let rest = &message[pos + marker.len()..];
+            let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+            if let Ok(line_no) = rest[..digits].parse() {
+                return Some(line_no);
+            }
+        }
+    }
+
+    None
+}
+
+/// Tracks the file currently being read by TeX.
+///
+/// TeX prints `(filename` when it opens a file and `)` when it closes it again.
+#[derive(Debug, Default)]
+struct FileStack(Vec<Option<String>>);
+
+impl FileStack {
+    /// Returns the innermost file currently open.
+    fn current(&self) -> Option<path::PathBuf> {
+        self.0.iter().rev().flatten().next().map(Into::into)
+    }
+
+    /// Updates the stack with the parentheses found in a log line.
+    fn scan(&mut self, line: &str) {
+        for (pos, c) in line.char_indices() {
+            match c {
+                '(' => {
+                    let rest = &line[pos + 1..];
+                    let len = rest
+                        .find(|c: char| c.is_whitespace() || c == '(' || c == ')')
+                        .unwrap_or(rest.len());
+                    let token = &rest[..len];
+
+                    self.0
+                        .push(Some(token.to_owned()).filter(|token| looks_like_file(token)));
+                }
+                ')' => {
+                    self.0.pop();
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Heuristically determines whether a token printed after `(` is a filename.
+fn looks_like_file(token: &str) -> bool {
+    if token.starts_with("./") || token.starts_with('/') {
+        return true;
+    }
+
+    match token.rfind('.') {
+        Some(dot) if dot > 0 => {
+            let ext = &token[dot + 1..];
+            !ext.is_empty() && ext.len() <= 4 && ext.bytes().all(|b| b.is_ascii_alphabetic())
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bibliography_errors, page_count, parse_log, Severity};
+    use std::path::Path;
+
+    #[test]
+    fn parses_file_line_error() {
+        let log = "\
+This is XeTeX, Version 3.141592653 (TeX Live 2022) (preloaded format=xelatex 2022.1.1)
+(./input.tex
+LaTeX2e <2021-11-15> patch level 1
+(/usr/share/texlive/texmf-dist/tex/latex/base/article.cls
+Document Class: article 2021/10/04 v1.4n Standard LaTeX document class
+)
+./input.tex:4: Undefined control sequence.
+l.4         \\foo
+                {bar}
+Here is how much of TeX's memory you used:
+";
+        let diagnostics = parse_log(log.as_bytes());
+        assert_eq!(diagnostics.len(), 1);
+
+        let diag = &diagnostics[0];
+        assert_eq!(diag.severity, Severity::Error);
+        assert_eq!(diag.file.as_deref(), Some(Path::new("./input.tex")));
+        assert_eq!(diag.line, Some(4));
+        assert_eq!(diag.message, "Undefined control sequence.");
+        assert_eq!(diag.context, ["l.4         \\foo", "                {bar}"]);
+    }
+
+    #[test]
+    fn parses_warnings() {
+        let log = "\
+(./input.tex
+(/usr/share/texlive/texmf-dist/tex/latex/hyperref/hyperref.sty
+Package hyperref Warning: Token not allowed in a PDF string (Unicode):
+(hyperref)                removing `\\foo' on input line 12.
+
+)
+LaTeX Warning: Reference `sec:intro' on page 1 undefined on input line 7.
+
+Overfull \\hbox (12.0pt too wide) in paragraph at lines 9--10
+[]\\TU/lmr/m/n/10 Somewhatlongword|
+
+Missing character: There is no ☃ in font lmroman10-regular!
+";
+        let diagnostics = parse_log(log.as_bytes());
+        assert_eq!(diagnostics.len(), 4);
+        assert!(diagnostics.iter().all(|d| d.severity == Severity::Warning));
+
+        assert_eq!(
+            diagnostics[0].message,
+            "Package hyperref Warning: Token not allowed in a PDF string (Unicode): \
+             removing `\\foo' on input line 12."
+        );
+        assert_eq!(diagnostics[0].line, Some(12));
+        assert_eq!(
+            diagnostics[0].file.as_deref(),
+            Some(Path::new(
+                "/usr/share/texlive/texmf-dist/tex/latex/hyperref/hyperref.sty"
+            ))
+        );
+
+        assert_eq!(diagnostics[1].line, Some(7));
+        assert_eq!(
+            diagnostics[1].file.as_deref(),
+            Some(Path::new("./input.tex"))
+        );
+
+        assert_eq!(diagnostics[2].line, Some(9));
+        assert_eq!(
+            diagnostics[2].context,
+            ["[]\\TU/lmr/m/n/10 Somewhatlongword|"]
+        );
+
+        assert!(diagnostics[3].message.starts_with("Missing character"));
+    }
+
+    #[test]
+    fn joins_wrapped_lines() {
+        let first = format!("LaTeX Warning: Citation `{}' on page 1 un", "x".repeat(40));
+        assert_eq!(first.len(), 79);
+        let log = format!("{}\ndefined on input line 3.\n\n", first);
+
+        let diagnostics = parse_log(log.as_bytes());
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0]
+            .message
+            .ends_with("page 1 undefined on input line 3."));
+        assert_eq!(diagnostics[0].line, Some(3));
+    }
+
+    #[test]
+    fn extracts_page_count() {
+        let log =
+            b"(./input.tex [1] [2] [3] )\nOutput written on input.xdv (3 pages, 7764 bytes).\n";
+        assert_eq!(page_count(log), Some(3));
+        assert_eq!(page_count(b"No pages of output.\n"), None);
+    }
+
+    #[test]
+    fn detects_undefined_citations() {
+        let log = b"\
+LaTeX Warning: Citation `knuth84' on page 1 undefined on input line 5.
+
+LaTeX Warning: Citation 'lamport94' on page 1 undefined on input line 6.
+
+LaTeX Warning: Reference `fig:1' on page 1 undefined on input line 7.
+
+";
+        let keys: Vec<_> = parse_log(log)
+            .iter()
+            .filter_map(|diag| diag.undefined_citation().map(str::to_owned))
+            .collect();
+        assert_eq!(keys, ["knuth84", "lamport94"]);
+    }
+
+    #[test]
+    fn parses_bibliography_errors() {
+        let bibtex = b"\
+This is BibTeX, Version 0.99d (TeX Live 2022)
+The top-level auxiliary file: input.aux
+I couldn't open database file missing.bib
+---line 3 of file input.aux
+ : \\bibdata{missing
+ :                }
+I'm skipping whatever remains of this command
+(There was 1 error message)
+";
+        assert_eq!(
+            bibliography_errors(bibtex),
+            ["I couldn't open database file missing.bib"]
+        );
+
+        let syntax_error = b"\
+This is BibTeX, Version 0.99d (TeX Live 2023)
+The top-level auxiliary file: input.aux
+The style file: plain.bst
+Database file #1: refs.bib
+I was expecting a `,' or a `}'---line 5 of file refs.bib
+ :   title
+ :         = {The Art of Computer Programming},
+I'm skipping whatever remains of this entry
+Warning--I didn't find a database entry for \"knuth84\"
+(There was 1 error message)
+";
+        assert_eq!(
+            bibliography_errors(syntax_error),
+            ["I was expecting a `,' or a `}'---line 5 of file refs.bib"]
+        );
+
+        let biber = b"\
+[0] Config.pm:307> INFO - This is Biber 2.17
+[65] Utils.pm:409> ERROR - Cannot find 'missing.bib'!
+[66] Biber.pm:134> INFO - ERRORS: 1
+";
+        assert_eq!(bibliography_errors(biber), ["Cannot find 'missing.bib'!"]);
+        assert!(bibliography_errors(b"(There were 0 error messages)\n").is_empty());
+    }
+}
@@ -0,0 +1,299 @@
+//! Progress events.
+//!
+//! While rendering, the output of every spawned process is read line by line and turned into
+//! `RenderEvent`s, which are passed to the callback registered through `TexRender::on_event`.
+//! Like log parsing, this is heuristic: events are derived from the messages printed by
+//! `latexmk` and the TeX engines.
+
+use crate::diagnostics::{is_continuation, strip_continuation, warning_prefix};
+use std::{ffi::OsStr, fmt, path, sync::Arc, sync::Mutex};
+
+/// An event occurring during a rendering.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RenderEvent {
+    /// A tool was started, e.g. `latexmk`, or `bibtex` by `latexmk`.
+    ToolStarted(String),
+    /// A TeX engine pass started, numbered from 1.
+    PassStarted(usize),
+    /// A page was shipped out, carrying the page number printed by TeX.
+    ///
+    /// Pages are shipped out again on every pass.
+    PageShipped(i64),
+    /// A warning was printed, with continuation lines joined.
+    Warning(String),
+}
+
+/// Callback receiving events.
+#[derive(Clone)]
+pub(crate) struct EventHandler(pub(crate) Arc<dyn Fn(&RenderEvent) + Send + Sync>);
+
+impl fmt::Debug for EventHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EventHandler")
+    }
+}
+
+/// Turns the output of the processes of a single rendering into events.
+///
+/// Lines may be passed in from multiple threads; the handler is called for one event at a time.
+#[derive(Debug)]
+pub(crate) struct EventParser {
+    /// Callback to pass events to.
+    handler: EventHandler,
+    /// Parsing state, also serializing calls to the handler.
+    state: Mutex<State>,
+}
+
+/// State of an `EventParser`.
+#[derive(Debug, Default)]
+struct State {
+    /// Number of engine passes started so far.
+    passes: usize,
+    /// Whether the output currently comes from a TeX engine.
+    in_engine: bool,
+    /// Warning that may still be continued, along with its continuation prefix.
+    warning: Option<(String, String)>,
+}
+
+impl EventParser {
+    /// Creates a new parser passing events to `handler`.
+    pub(crate) fn new(handler: EventHandler) -> Self {
+        EventParser {
+            handler,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Reports the start of a process running `program`.
+    pub(crate) fn command_started(&self, program: &OsStr) {
+        let mut state = self.state.lock().expect("lock poisoned");
+        self.flush(&mut state);
+        state.in_engine = false;
+
+        let name = path::Path::new(program)
+            .file_name()
+            .unwrap_or(program)
+            .to_string_lossy();
+        (self.handler.0)(&RenderEvent::ToolStarted(name.into_owned()));
+    }
+
+    /// Reports the end of the current process.
+    pub(crate) fn command_finished(&self) {
+        let mut state = self.state.lock().expect("lock poisoned");
+        self.flush(&mut state);
+        state.in_engine = false;
+    }
+
+    /// Parses a single line of output, without its line terminator.
+    pub(crate) fn line(&self, line: &[u8]) {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end_matches('\r');
+        let mut state = self.state.lock().expect("lock poisoned");
+
+        if let Some((ref mut message, ref prefix)) = state.warning {
+            if is_continuation(line, prefix) {
+                message.push(' ');
+                message.push_str(strip_continuation(line, prefix));
+                return;
+            }
+        }
+        self.flush(&mut state);
+
+        if let Some(tool) = latexmk_tool(line) {
+            state.in_engine = false;
+            (self.handler.0)(&RenderEvent::ToolStarted(tool.to_owned()));
+        } else if is_engine_banner(line) {
+            state.passes += 1;
+            state.in_engine = true;
+            (self.handler.0)(&RenderEvent::PassStarted(state.passes));
+        } else if let Some(prefix) = warning_prefix(line) {
+            state.warning = Some((line.trim_end().to_owned(), prefix));
+        } else if line.starts_with("Overfull \\") || line.starts_with("Underfull \\") {
+            (self.handler.0)(&RenderEvent::Warning(line.trim_end().to_owned()));
+        } else if line.contains(".xdv -> ") || line.contains(".dvi -> ") {
+            // The DVI driver run by XeTeX prints page numbers as well.
+            state.in_engine = false;
+        } else if state.in_engine {
+            for page in shipped_pages(line) {
+                (self.handler.0)(&RenderEvent::PageShipped(page));
+            }
+        }
+    }
+
+    /// Emits the pending warning, if any.
+    fn flush(&self, state: &mut State) {
+        if let Some((message, _)) = state.warning.take() {
+            (self.handler.0)(&RenderEvent::Warning(message));
+        }
+    }
+}
+
+/// Extracts the tool from a `latexmk` line like `Running 'pdflatex -interaction=...'`.
+fn latexmk_tool(line: &str) -> Option<&str> {
+    let command = line.strip_prefix("Running '")?;
+    let program = command
+        .trim_start_matches('"')
+        .split(|c: char| c.is_whitespace() || c == '"' || c == '\'')
+        .next()?;
+    let name = program.rsplit(['/', '\\']).next()?;
+    Some(name).filter(|name| !name.is_empty())
+}
+
+/// Checks whether a line is printed by a TeX engine starting up, e.g. `This is pdfTeX, ...`.
+///
+/// Tectonic reports its passes as `Running TeX ...` and `Rerunning TeX ...` instead.
+fn is_engine_banner(line: &str) -> bool {
+    if let Some(rest) = line.strip_prefix("This is ") {
+        let name = rest.split(',').next().unwrap_or_default();
+        return name.ends_with("TeX") && !name.contains(' ') && !name.contains("Bib");
+    }
+
+    let note = line.strip_prefix("note: ").unwrap_or(line);
+    note.starts_with("Running TeX") || note.starts_with("Rerunning TeX")
+}
+
+/// Extracts the page numbers TeX prints when shipping out pages, like `[1] [2{pdftex.map}]`.
+fn shipped_pages(line: &str) -> Vec<i64> {
+    let mut pages = Vec::new();
+    let mut previous = ' ';
+
+    for (pos, c) in line.char_indices() {
+        if c == '[' && !previous.is_alphanumeric() {
+            let rest = &line[pos + 1..];
+            let len = rest
+                .char_indices()
+                .find(|&(idx, c)| !(c.is_ascii_digit() || (idx == 0 && c == '-')))
+                .map_or(rest.len(), |(idx, _)| idx);
+            let terminated = rest[len..]
+                .chars()
+                .next()
+                .map_or(true, |c| matches!(c, ']' | '{' | '<' | ' '));
+            if let Ok(page) = rest[..len].parse() {
+                if terminated {
+                    pages.push(page);
+                }
+            }
+        }
+        previous = c;
+    }
+
+    pages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EventHandler, EventParser, RenderEvent};
+    use std::{
+        ffi::OsStr,
+        sync::{Arc, Mutex},
+    };
+
+    fn parse(commands: &[(&str, &str)]) -> Vec<RenderEvent> {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let parser = {
+            let events = events.clone();
+            EventParser::new(EventHandler(Arc::new(move |event: &RenderEvent| {
+                events.lock().unwrap().push(event.clone())
+            })))
+        };
+
+        for (program, output) in commands {
+            parser.command_started(OsStr::new(program));
+            for line in output.lines() {
+                parser.line(line.as_bytes());
+            }
+            parser.command_finished();
+        }
+
+        let events = events.lock().unwrap().clone();
+        events
+    }
+
+    #[test]
+    fn parses_latexmk_output() {
+        let output = "Rc files read:
+  NONE
+Latexmk: This is Latexmk, John Collins, 7 Jan. 2023. Version 4.79.
+Run number 1 of rule 'pdflatex'
+Running 'pdflatex  -interaction=nonstopmode -file-line-error  \"input.tex\"'
+This is pdfTeX, Version 3.141592653-2.6-1.40.25 (TeX Live 2023) (preloaded format=pdflatex)
+(./input.tex
+LaTeX2e <2022-11-01> patch level 1
+(/usr/share/texlive/texmf-dist/tex/latex/base/article.cls
+Document Class: article 2022/07/02 v1.4n Standard LaTeX document class
+(/usr/share/texlive/texmf-dist/tex/latex/base/size10.clo))
+LaTeX Warning: Citation `knuth' on page 1 undefined on input line 5.
+
+Package hyperref Warning: Token not allowed in a PDF string (Unicode):
+(hyperref)                removing `math shift' on input line 7.
+
+[1{/usr/share/texlive/texmf-var/fonts/map/pdftex/updmap/pdftex.map}] [2] (./input.aux) )
+Output written on input.pdf (2 pages, 12345 bytes).
+Run number 1 of rule 'bibtex input'
+Running 'bibtex  \"input.aux\"'
+This is BibTeX, Version 0.99d (TeX Live 2023)
+Run number 2 of rule 'pdflatex'
+Running 'pdflatex  -interaction=nonstopmode -file-line-error  \"input.tex\"'
+This is pdfTeX, Version 3.141592653-2.6-1.40.25 (TeX Live 2023) (preloaded format=pdflatex)
+Overfull \\hbox (15.0pt too wide) in paragraph at lines 3--4
+[1] [2]
+";
+
+        assert_eq!(
+            parse(&[("/usr/bin/latexmk", output)]),
+            [
+                RenderEvent::ToolStarted("latexmk".to_owned()),
+                RenderEvent::ToolStarted("pdflatex".to_owned()),
+                RenderEvent::PassStarted(1),
+                RenderEvent::Warning(
+                    "LaTeX Warning: Citation `knuth' on page 1 undefined on input line 5."
+                        .to_owned()
+                ),
+                RenderEvent::Warning(
+                    "Package hyperref Warning: Token not allowed in a PDF string (Unicode): \
+                     removing `math shift' on input line 7."
+                        .to_owned()
+                ),
+                RenderEvent::PageShipped(1),
+                RenderEvent::PageShipped(2),
+                RenderEvent::ToolStarted("bibtex".to_owned()),
+                RenderEvent::ToolStarted("pdflatex".to_owned()),
+                RenderEvent::PassStarted(2),
+                RenderEvent::Warning(
+                    "Overfull \\hbox (15.0pt too wide) in paragraph at lines 3--4".to_owned()
+                ),
+                RenderEvent::PageShipped(1),
+                RenderEvent::PageShipped(2),
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_dvi_driver_pages() {
+        let output = "This is XeTeX, Version 3.141592653-2.6-0.999995 (TeX Live 2023)
+(./input.tex [1] [2] )
+Output written on input.xdv (2 pages, 1234 bytes).
+input.xdv -> input.pdf
+[1][2]
+12345 bytes written
+";
+
+        assert_eq!(
+            parse(&[("xelatex", output)]),
+            [
+                RenderEvent::ToolStarted("xelatex".to_owned()),
+                RenderEvent::PassStarted(1),
+                RenderEvent::PageShipped(1),
+                RenderEvent::PageShipped(2),
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_brackets_outside_shipouts() {
+        assert_eq!(
+            super::shipped_pages("see [1] and x[2] or [3a] [-1]"),
+            [1, -1]
+        );
+    }
+}
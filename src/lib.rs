@@ -4,12 +4,37 @@
 //!
 //! Also supports generation of LaTeX documents, see the `tpl` module.
 
+pub mod backend;
+pub mod cache;
+pub mod diagnostics;
+pub mod events;
+mod limits;
+pub mod report;
+mod runner;
+pub mod search_path;
+pub mod synctex;
 pub mod tex_escape;
+pub mod toolchain;
 pub mod tpl;
+mod untrusted;
 
+use backend::{BackendRun, RenderBackend, RenderJob};
+use cache::RenderCache;
+use diagnostics::{Diagnostic, Severity};
+use events::{EventHandler, EventParser, RenderEvent};
+use limits::ResourceLimits;
+use search_path::{DefaultPath, SearchPaths, SearchVariable};
 use std::{
+    env,
     ffi::{OsStr, OsString},
-    fs, io, path, process,
+    fs,
+    io::{self, Read, Write},
+    path, process,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 use thiserror::Error;
 
@@ -18,35 +43,327 @@ use thiserror::Error;
 /// Creating a new rendering command usually starts by supplying a LaTeX-document, either via
 /// `from_bytes` or `from_file`. Other options can be set through the various builder methods.
 ///
+/// Documents spread across multiple files can be rendered by creating the command with
+/// `from_sources` or `from_dir` instead, which also select the main file to render. Output files
+/// are named after the main file, e.g. `thesis.tex` renders to `thesis.pdf`; documents created
+/// from a single file are rendered as `input.tex`.
+///
 /// Once satisfied, the `render` method will perform the call to the external TeX-engine and return
-/// a rendered PDF as raw bytes.
+/// a `RenderOutput`, holding the rendered document as raw bytes along with any warnings issued. PDF
+/// is rendered by default, other formats can be selected using `output_format`.
+///
+/// # Backends
+///
+/// Rendering is performed by `latexmk` by default. Other toolchains, e.g. invoking the engine
+/// directly or using Tectonic, can be selected through `backend`, see the `backend` module.
 ///
-/// # TEXINPUTS
+/// # Search paths
 ///
 /// The search path for classes, includes or other files used in TeX can be extended using the
 /// `TEXINPUTS` environment variable; `TexRender` sets this variable when rendering. See
 /// `add_texinput` for details.
 ///
+/// The same search path is used for BibTeX databases and styles, through `BIBINPUTS` and
+/// `BSTINPUTS`. Bibliographies are processed by BibTeX or Biber, see `bibliography`.
+///
+/// Other kpathsea variables, e.g. for fonts or Lua modules, can be set individually through
+/// `add_search_path`, see the `search_path` module.
+///
 /// # Assets
 ///
 /// Instead of adding a folder to `TEXINPUTS`, any sort of external file can be added as an asset.
 /// Assets are stored in a temporary folder that lives as long as the `TexRender` instance, the
-/// folder will automatically be added to `TEXINPUTS` when rendering. See the `add_asset_*`
+/// folder will automatically be added to all search paths when rendering. See the `add_asset_*`
 /// functions for details.
+///
+/// # Untrusted input
+///
+/// Documents from untrusted sources should be rendered with `untrusted` enabled, which restricts
+/// file access and refuses constructs that escape to the shell.
+///
+/// # Build directory
+///
+/// By default, every rendering happens in a fresh temporary directory. Setting a persistent
+/// directory through `build_dir` preserves auxiliary files between renderings, allowing `latexmk`
+/// to skip passes that are not required.
+///
+/// # Caching
+///
+/// Results of identical renderings can be reused by attaching a `RenderCache`, see the `cache`
+/// module for details.
 #[derive(Debug)]
 pub struct TexRender {
-    /// Content to render.
-    source: Vec<u8>,
-    /// A number of folders to add to `TEXINPUTS`.
-    texinputs: Vec<path::PathBuf>,
-    /// Path to latexmk.
-    latex_mk_path: path::PathBuf,
-    /// Whether or not to use XeLaTeX.
-    use_xelatex: bool,
-    /// Whether or not to allow shell escaping.
-    allow_shell_escape: bool,
+    /// Source files, with paths relative to the project root.
+    sources: Vec<(path::PathBuf, Vec<u8>)>,
+    /// Path of the main source file, relative to the project root.
+    main: path::PathBuf,
+    /// Folders to add to the kpathsea search paths.
+    search_paths: SearchPaths,
+    /// Backend running the toolchain.
+    backend: Box<dyn RenderBackend>,
+    /// Path to dvisvgm.
+    dvisvgm_path: path::PathBuf,
+    /// TeX engine to use.
+    engine: TexEngine,
+    /// Format to output.
+    output_format: OutputFormat,
+    /// Shell escape policy.
+    shell_escape: ShellEscape,
+    /// Commands allowed in restricted shell escape mode, if not the system defaults.
+    shell_escape_commands: Option<Vec<String>>,
+    /// Tool processing the bibliography, if any.
+    bibliography: Option<BibBackend>,
+    /// Whether the input is untrusted.
+    untrusted: bool,
+    /// Date embedded into the output in reproducible mode, if enabled.
+    source_date: Option<SystemTime>,
+    /// Whether to write SyncTeX data.
+    synctex: bool,
+    /// Environment variables to set, or remove if `None`.
+    env: Vec<(OsString, Option<OsString>)>,
+    /// Whether spawned processes inherit the environment.
+    inherit_env: bool,
+    /// Additional arguments for the backend's main command.
+    args: Vec<OsString>,
+    /// Additional arguments for every engine pass.
+    engine_args: Vec<OsString>,
+    /// Contents of an additional `latexmkrc`.
+    latexmkrc: Option<String>,
+    /// Filenames or extensions of intermediate files to return.
+    artifacts: Vec<String>,
+    /// Limits on the resources used by the spawned processes.
+    limits: ResourceLimits,
     /// Temporary directory holding assets to be included.
     assets_dir: Option<tempdir::TempDir>,
+    /// Persistent build directory, if any.
+    build_dir: Option<path::PathBuf>,
+    /// Maximum wall-clock time a rendering may take.
+    timeout: Option<Duration>,
+    /// Token to abort a running rendering.
+    cancellation_token: Option<CancellationToken>,
+    /// Cache for rendering results.
+    cache: Option<RenderCache>,
+    /// Callback receiving progress events, if any.
+    on_event: Option<EventHandler>,
+    /// Packages and classes checked by `preflight`, as filenames.
+    required_files: Vec<String>,
+}
+
+/// Token to cancel a running rendering.
+///
+/// Tokens are cheap to clone, all clones refer to the same cancellation state. Once cancelled, a
+/// token stays cancelled; any rendering using it will be aborted.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// Creates a new, not yet cancelled token.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels all renderings using this token.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns whether the token has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// TeX engine used to render a document.
+///
+/// Each engine maps onto the respective `latexmk` switch that selects both the engine and PDF
+/// output. The default engine is XeLaTeX.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TexEngine {
+    /// pdfLaTeX (`latexmk -pdf`).
+    PdfLatex,
+    /// XeLaTeX (`latexmk -pdfxe`).
+    XeLatex,
+    /// LuaLaTeX (`latexmk -pdflua`).
+    LuaLatex,
+}
+
+impl TexEngine {
+    /// Returns the `latexmk` command line switch selecting the engine.
+    pub fn latexmk_arg(self) -> &'static str {
+        match self {
+            TexEngine::PdfLatex => "-pdf",
+            TexEngine::XeLatex => "-pdfxe",
+            TexEngine::LuaLatex => "-pdflua",
+        }
+    }
+
+    /// Returns the name of the engine's executable.
+    pub fn executable(self) -> &'static str {
+        match self {
+            TexEngine::PdfLatex => "pdflatex",
+            TexEngine::XeLatex => "xelatex",
+            TexEngine::LuaLatex => "lualatex",
+        }
+    }
+}
+
+impl Default for TexEngine {
+    #[inline]
+    fn default() -> Self {
+        TexEngine::XeLatex
+    }
+}
+
+/// Output format of a rendering.
+///
+/// The default output format is PDF.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum OutputFormat {
+    /// Portable Document Format.
+    Pdf,
+    /// Device independent file format.
+    ///
+    /// XeLaTeX produces extended DVI (`.xdv`) files instead.
+    Dvi,
+    /// PostScript, converted from DVI using `dvips`.
+    ///
+    /// Not supported by XeLaTeX.
+    PostScript,
+    /// Scalable Vector Graphics, one file per page, converted from DVI using `dvisvgm`.
+    ///
+    /// Glyphs are converted to paths, the resulting files do not depend on any fonts.
+    Svg,
+}
+
+impl Default for OutputFormat {
+    #[inline]
+    fn default() -> Self {
+        OutputFormat::Pdf
+    }
+}
+
+/// Policy for running shell commands from within TeX through `\write18`.
+///
+/// Packages like `minted` or `svg` rely on shell escape to call external programs. The default
+/// is to disable shell escape.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ShellEscape {
+    /// No shell commands may be run (`-no-shell-escape`).
+    Disabled,
+    /// Only commands on an allowlist may be run (`-shell-restricted`).
+    ///
+    /// The allowlist defaults to the `shell_escape_commands` setting of the TeX installation, see
+    /// `TexRender::shell_escape_commands` to override it.
+    Restricted,
+    /// Any shell command may be run (`-shell-escape`).
+    Full,
+}
+
+impl ShellEscape {
+    /// Returns the `latexmk` command line switch selecting the policy.
+    pub fn latexmk_arg(self) -> &'static str {
+        match self {
+            ShellEscape::Disabled => "-no-shell-escape",
+            ShellEscape::Restricted => "-shell-restricted",
+            ShellEscape::Full => "-shell-escape",
+        }
+    }
+}
+
+impl Default for ShellEscape {
+    #[inline]
+    fn default() -> Self {
+        ShellEscape::Disabled
+    }
+}
+
+/// Tool used to process bibliographies.
+///
+/// The backend must match the setup of the document: `\bibliography` and `natbib` use BibTeX,
+/// `biblatex` uses Biber unless loaded with `backend=bibtex`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BibBackend {
+    /// BibTeX.
+    Bibtex,
+    /// Biber.
+    Biber,
+}
+
+impl BibBackend {
+    /// Returns the name of the tool's executable.
+    pub fn executable(self) -> &'static str {
+        match self {
+            BibBackend::Bibtex => "bibtex",
+            BibBackend::Biber => "biber",
+        }
+    }
+}
+
+/// Resource whose use can be limited.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Resource {
+    /// CPU time of a single process.
+    CpuTime,
+    /// Address space of a single process.
+    AddressSpace,
+    /// Size of a single written file.
+    FileSize,
+    /// Number of files in the build directory.
+    FileCount,
+}
+
+/// A single file produced by a rendering.
+#[derive(Clone, Debug)]
+pub struct OutputFile {
+    /// Filename, named after the main source file.
+    pub name: String,
+    /// Contents of the file.
+    pub data: Vec<u8>,
+}
+
+/// Summary of a successful rendering.
+///
+/// Returned by renderings that write their output directly to a destination.
+#[derive(Clone, Debug)]
+pub struct RenderSummary {
+    /// Warnings parsed from the LaTeX log, e.g. undefined references or overfull boxes.
+    pub warnings: Vec<Diagnostic>,
+    /// Number of pages in the output, if reported by the engine.
+    pub pages: Option<u32>,
+    /// Number of times the TeX engine was run.
+    pub passes: usize,
+    /// Intermediate files requested through `TexRender::add_artifact`, sorted by name.
+    pub artifacts: Vec<OutputFile>,
+}
+
+/// Result of a successful rendering.
+#[derive(Clone, Debug)]
+pub struct RenderOutput {
+    /// The rendered files.
+    ///
+    /// Contains a single file for all output formats except SVG, which produces one file per page.
+    pub files: Vec<OutputFile>,
+    /// Warnings parsed from the LaTeX log, e.g. undefined references or overfull boxes.
+    pub warnings: Vec<Diagnostic>,
+    /// Number of pages in the output, if reported by the engine.
+    pub pages: Option<u32>,
+    /// Number of times the TeX engine was run.
+    pub passes: usize,
+    /// Intermediate files requested through `TexRender::add_artifact`, sorted by name.
+    pub artifacts: Vec<OutputFile>,
+}
+
+impl RenderOutput {
+    /// Creates a new render output from files and a summary.
+    fn new(files: Vec<OutputFile>, summary: RenderSummary) -> Self {
+        RenderOutput {
+            files,
+            warnings: summary.warnings,
+            pages: summary.pages,
+            passes: summary.passes,
+            artifacts: summary.artifacts,
+        }
+    }
 }
 
 /// Error occuring during rendering.
@@ -55,17 +372,30 @@ pub enum RenderingError {
     /// Temporary directry could not be created.
     #[error("could not create temporary directory: {0}")]
     TempdirCreation(io::Error),
+    /// Persistent build directory could not be created.
+    #[error("could not create build directory: {0}")]
+    BuildDirCreation(io::Error),
     /// Writing the input file failed.
     #[error("could not write input file: {0}")]
     WriteInputFile(io::Error),
     /// Reading the resulting output file failed.
     #[error("could not read output file: {0}")]
     ReadOutputFile(io::Error),
+    /// Writing the output to its destination failed.
+    #[error("could not write output: {0}")]
+    WriteOutput(io::Error),
+    /// The output format produces multiple files, but only a single destination was given.
+    #[error("{0:?} output consists of multiple files")]
+    MultipleOutputFiles(OutputFormat),
     /// Could not run LaTeX rendering command.
-    #[error("could not run latexmk: {0}")]
+    #[error("could not run rendering command: {0}")]
     RunError(io::Error),
-    /// latexmk failed.
-    #[error("LaTeX failure: {stdout:?} {stderr:?}")]
+    /// A command run by the backend failed.
+    #[error(
+        "LaTeX failure ({}): {}",
+        report::exit_status(.status),
+        report::first_message(.stdout, .stderr, .diagnostics)
+    )]
     LatexError {
         /// Process exit code.
         status: Option<i32>,
@@ -73,19 +403,190 @@ pub enum RenderingError {
         stdout: Vec<u8>,
         /// Content of stderr.
         stderr: Vec<u8>,
+        /// Diagnostics parsed from the LaTeX log file.
+        ///
+        /// Empty if no log file was written.
+        diagnostics: Vec<Diagnostic>,
+    },
+    /// Converting the rendered document to the output format failed.
+    #[error(
+        "conversion failure ({tool}, {}): {}",
+        report::exit_status(.status),
+        report::last_line(.stderr)
+    )]
+    ConversionError {
+        /// Name of the conversion tool.
+        tool: &'static str,
+        /// Process exit code.
+        status: Option<i32>,
+        /// Content of stderr.
+        stderr: Vec<u8>,
     },
+    /// The engine cannot produce the requested output format.
+    #[error("{engine:?} does not support {format:?} output")]
+    UnsupportedOutputFormat {
+        /// Selected engine.
+        engine: TexEngine,
+        /// Requested output format.
+        format: OutputFormat,
+    },
+    /// The backend does not support the selected engine.
+    #[error("{backend} backend does not support {engine:?}")]
+    UnsupportedEngine {
+        /// Name of the backend.
+        backend: &'static str,
+        /// Selected engine.
+        engine: TexEngine,
+    },
+    /// Rendering did not finish in time and was aborted.
+    #[error("rendering timed out after {0:?}")]
+    Timeout(Duration),
+    /// Rendering was aborted through its cancellation token.
+    #[error("rendering was cancelled")]
+    Cancelled,
+    /// A spawned process exceeded a resource limit.
+    #[error("{0:?} limit exceeded")]
+    ResourceLimitExceeded(Resource),
+    /// Reading from or writing to the render cache failed.
+    #[error("render cache failure: {0}")]
+    Cache(io::Error),
+    /// The bibliography tool reported errors.
+    #[error("{} failure: {}", backend.executable(), messages.join("; "))]
+    BibliographyError {
+        /// Tool that failed.
+        backend: BibBackend,
+        /// Error messages parsed from the tool's log file.
+        messages: Vec<String>,
+    },
+    /// The document cites keys missing from the bibliography.
+    #[error("undefined citations: {}", .0.join(", "))]
+    UndefinedCitations(Vec<String>),
+    /// A source file uses a construct that is refused for untrusted input.
+    #[error("{}:{line}: {construct} is not allowed in untrusted input", file.display())]
+    ForbiddenConstruct {
+        /// Source file containing the construct.
+        file: path::PathBuf,
+        /// Line of the construct.
+        line: u32,
+        /// Description of the construct, e.g. `\write18`.
+        construct: String,
+    },
+    /// An untrusted document tried to access files outside the build directory and search paths.
+    #[error("access to files denied: {}", .0.join(", "))]
+    ForbiddenFileAccess(Vec<String>),
+    /// An option set on the rendering is invalid, e.g. an argument overriding a safety setting.
+    #[error("invalid option: {0}")]
+    InvalidOption(String),
 }
 
+/// Environment variables controlled by `TexRender`, which cannot be set or removed.
+///
+/// Search path variables are protected as well.
+const PROTECTED_ENV: &[&str] = &[
+    "openin_any",
+    "openout_any",
+    "shell_escape",
+    "shell_escape_commands",
+    "TEXMFOUTPUT",
+    "SOURCE_DATE_EPOCH",
+    "FORCE_SOURCE_DATE",
+];
+
+/// Prefixes of inherited environment variables that influence the output, added to cache keys.
+///
+/// Prefixes also cover kpathsea's program-specific variants, e.g. `TEXINPUTS.pdflatex`.
+const KEYED_ENV_PREFIXES: &[&str] = &[
+    "TEXMF",
+    "TEXINPUTS",
+    "TEXFONTS",
+    "TEXFORMATS",
+    "BIBINPUTS",
+    "BSTINPUTS",
+    "OSFONTDIR",
+    "LUAINPUTS",
+    "INDEXSTYLE",
+    "SOURCE_DATE_EPOCH",
+    "FORCE_SOURCE_DATE",
+    "openin_any",
+    "openout_any",
+    "shell_escape",
+];
+
+/// Options of `latexmk`, the engines and Tectonic that may be passed through `add_arg` and
+/// `add_engine_arg`, without leading dashes or values.
+///
+/// All of them have options running commands or writing files anywhere, e.g. `-output-driver`,
+/// `-deps-out` or `--outdir`. Only options known to be harmless are accepted, as a list of
+/// dangerous ones would never be complete.
+const ALLOWED_ARGS: &[&str] = &[
+    // latexmk
+    "silent",
+    "quiet",
+    "verbose",
+    "time",
+    "max-repeat",
+    // TeX engines
+    "halt-on-error",
+    "file-line-error",
+    "no-file-line-error",
+    "synctex",
+    "draftmode",
+    "8bit",
+    "no-parse-first-line",
+    "no-mktex",
+    "output-comment",
+    "nosocket",
+    "safer",
+    "utc",
+    // Tectonic
+    "keep-logs",
+    "keep-intermediates",
+    "print",
+    "chatter",
+    "only-cached",
+    "untrusted",
+    "reruns",
+];
+
+/// Options allowed in addition to `ALLOWED_ARGS` unless the input is untrusted.
+///
+/// `-recorder` writes a list of every file opened, readable by the document.
+const TRUSTED_ARGS: &[&str] = &["recorder"];
+
+/// Number of staging files created by `render_to_path`, making their names unique per call.
+static STAGING_FILES: AtomicUsize = AtomicUsize::new(0);
+
 impl TexRender {
     /// Create a new tex render configuration using raw input bytes as the source file.
     pub fn from_bytes(source: Vec<u8>) -> TexRender {
         TexRender {
-            source,
-            texinputs: Vec::new(),
-            latex_mk_path: "latexmk".into(),
-            use_xelatex: true,
-            allow_shell_escape: false,
+            sources: vec![("input.tex".into(), source)],
+            main: "input.tex".into(),
+            search_paths: SearchPaths::default(),
+            backend: Box::new(backend::Latexmk::default()),
+            dvisvgm_path: "dvisvgm".into(),
+            engine: TexEngine::default(),
+            output_format: OutputFormat::default(),
+            shell_escape: ShellEscape::default(),
+            shell_escape_commands: None,
+            bibliography: None,
+            untrusted: false,
+            source_date: None,
+            synctex: false,
+            env: Vec::new(),
+            inherit_env: true,
+            args: Vec::new(),
+            engine_args: Vec::new(),
+            latexmkrc: None,
+            artifacts: Vec::new(),
+            limits: ResourceLimits::default(),
             assets_dir: None,
+            build_dir: None,
+            timeout: None,
+            cancellation_token: None,
+            cache: None,
+            on_event: None,
+            required_files: Vec::new(),
         }
     }
 
@@ -94,29 +595,102 @@ impl TexRender {
         Ok(Self::from_bytes(fs::read(source)?))
     }
 
+    /// Create a new tex render configuration from a set of source files.
+    ///
+    /// Each source is given as a path relative to the project root, e.g. `chapters/intro.tex`,
+    /// along with its contents. `main` is the path of the document to render, it must be one of
+    /// the sources.
+    ///
+    /// Returns an error if a path is absolute or points outside the project root, or if `main` is
+    /// missing.
+    pub fn from_sources<I, P, M>(sources: I, main: M) -> io::Result<TexRender>
+    where
+        I: IntoIterator<Item = (P, Vec<u8>)>,
+        P: Into<path::PathBuf>,
+        M: Into<path::PathBuf>,
+    {
+        let sources: Vec<(path::PathBuf, Vec<u8>)> = sources
+            .into_iter()
+            .map(|(path, contents)| (path.into(), contents))
+            .collect();
+        let main = main.into();
+
+        for (path, _) in &sources {
+            check_project_path(path)?;
+        }
+
+        if main.file_stem().is_none() || !sources.iter().any(|(path, _)| *path == main) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("main file {} not found in sources", main.display()),
+            ));
+        }
+
+        let mut tex = Self::from_bytes(Vec::new());
+        tex.sources = sources;
+        tex.main = main;
+        Ok(tex)
+    }
+
+    /// Create a new tex render configuration from a project directory.
+    ///
+    /// All files below `dir` are read, except for hidden files and directories (whose name
+    /// starts with a `.`). `main` is the path of the document to render, relative to `dir`.
+    pub fn from_dir<P, M>(dir: P, main: M) -> io::Result<TexRender>
+    where
+        P: AsRef<path::Path>,
+        M: Into<path::PathBuf>,
+    {
+        let mut sources = Vec::new();
+        read_project_dir(dir.as_ref(), path::Path::new(""), &mut sources)?;
+        Self::from_sources(sources, main)
+    }
+
+    /// Returns the path of the assets dir, creating it if not present.
+    fn assets_path(&mut self) -> io::Result<&path::Path> {
+        if self.assets_dir.is_none() {
+            let assets_dir = tempdir::TempDir::new("texrender-assets")?;
+            for var in SearchVariable::ALL.iter() {
+                self.search_paths
+                    .add(*var, assets_dir.path().to_owned(), false);
+            }
+            self.assets_dir = Some(assets_dir);
+        }
+
+        Ok(self
+            .assets_dir
+            .as_ref()
+            .expect("assets dir just created")
+            .path())
+    }
+
     /// Adds an asset to the texrender.
     pub fn add_asset_from_bytes<S: AsRef<OsStr>>(
         &mut self,
         filename: S,
         bytes: &[u8],
     ) -> io::Result<()> {
-        // Initialize assets dir, if not present.
-        let assets_path = match self.assets_dir {
-            Some(ref assets_dir) => assets_dir.path(),
-            None => {
-                let assets_dir = tempdir::TempDir::new("texrender-assets")?;
-                self.texinputs.push(assets_dir.path().to_owned());
-                self.assets_dir = Some(assets_dir);
-                &self.texinputs[self.texinputs.len() - 1]
-            }
-        };
-
-        let output_fn = assets_path.join(filename.as_ref());
+        let output_fn = self.assets_path()?.join(filename.as_ref());
         fs::create_dir_all(output_fn.parent().expect("filename has no parent?"))?;
 
         fs::write(output_fn, bytes)
     }
 
+    /// Adds an asset to the texrender asynchronously.
+    ///
+    /// See `add_asset_from_bytes` for details.
+    #[cfg(feature = "tokio")]
+    pub async fn add_asset_from_bytes_async<S: AsRef<OsStr>>(
+        &mut self,
+        filename: S,
+        bytes: &[u8],
+    ) -> io::Result<()> {
+        let output_fn = self.assets_path()?.join(filename.as_ref());
+        tokio::fs::create_dir_all(output_fn.parent().expect("filename has no parent?")).await?;
+
+        tokio::fs::write(output_fn, bytes).await
+    }
+
     /// Adds an assets to the texrender from a file.
     ///
     /// # Panics
@@ -130,97 +704,2508 @@ impl TexRender {
         self.add_asset_from_bytes(filename, &buf)
     }
 
+    /// Adds a bibliography database to the texrender.
+    ///
+    /// The database is stored as an asset and can be referenced by its filename, e.g.
+    /// `\bibliography{refs}` or `\addbibresource{refs.bib}` for `refs.bib`.
+    pub fn add_bib_database<S: AsRef<OsStr>>(
+        &mut self,
+        filename: S,
+        bytes: &[u8],
+    ) -> io::Result<()> {
+        self.add_asset_from_bytes(filename, bytes)
+    }
+
+    /// Adds a BibTeX style to the texrender.
+    ///
+    /// The style is stored as an asset and can be referenced by its filename without extension,
+    /// e.g. `\bibliographystyle{house}` for `house.bst`.
+    pub fn add_bib_style<S: AsRef<OsStr>>(&mut self, filename: S, bytes: &[u8]) -> io::Result<()> {
+        self.add_asset_from_bytes(filename, bytes)
+    }
+
     /// Adds a path to list of texinputs.
+    ///
+    /// The path is also searched for BibTeX databases and styles.
     pub fn add_texinput<P: Into<path::PathBuf>>(&mut self, input_path: P) -> &mut Self {
-        self.texinputs.push(input_path.into());
+        let input_path = input_path.into();
+        for var in &[
+            SearchVariable::TexInputs,
+            SearchVariable::BibInputs,
+            SearchVariable::BstInputs,
+        ] {
+            self.search_paths.add(*var, input_path.clone(), false);
+        }
+        self
+    }
+
+    /// Adds a path to a kpathsea search path.
+    ///
+    /// Folders are searched in the order they were added. Only the folder itself is searched,
+    /// see `add_search_path_recursive` to include its subfolders.
+    pub fn add_search_path<P: Into<path::PathBuf>>(
+        &mut self,
+        var: SearchVariable,
+        path: P,
+    ) -> &mut Self {
+        self.search_paths.add(var, path.into(), false);
+        self
+    }
+
+    /// Adds a path to a kpathsea search path, including all of its subfolders.
+    ///
+    /// The path is added as a `//` entry. Note that kpathsea scans recursive entries on every
+    /// lookup unless an `ls-R` database is present, which can be slow for large trees.
+    pub fn add_search_path_recursive<P: Into<path::PathBuf>>(
+        &mut self,
+        var: SearchVariable,
+        path: P,
+    ) -> &mut Self {
+        self.search_paths.add(var, path.into(), true);
+        self
+    }
+
+    /// Sets whether the system default path is searched before, after or instead of the added
+    /// folders.
+    ///
+    /// If not set, the default path is searched first. Note that without the default path, TeX
+    /// will not find any files of the TeX installation.
+    pub fn default_search_path(&mut self, var: SearchVariable, default: DefaultPath) -> &mut Self {
+        self.search_paths.set_default(var, default);
         self
     }
 
-    /// Sets the path of `latexmk`.
+    /// Sets the path of `latexmk`, selecting the `latexmk` backend.
     ///
     /// If not set, will look for `latexmk` on the current `PATH`.
     pub fn latex_mk_path<P: Into<path::PathBuf>>(&mut self, latex_mk_path: P) -> &mut Self {
-        self.latex_mk_path = latex_mk_path.into();
+        let mut latexmk = backend::Latexmk::new();
+        latexmk.path(latex_mk_path);
+        self.backend(latexmk)
+    }
+
+    /// Sets the backend running the toolchain.
+    ///
+    /// If not set, `latexmk` is used. See the `backend` module for the available backends.
+    pub fn backend<B: RenderBackend + 'static>(&mut self, backend: B) -> &mut Self {
+        self.backend = Box::new(backend);
         self
     }
 
-    /// Renders the given source as PDF.
-    pub fn render(&self) -> Result<Vec<u8>, RenderingError> {
-        let tmp = tempdir::TempDir::new("texrender").map_err(RenderingError::TempdirCreation)?;
-        let input_file = tmp.path().join("input.tex");
-        let output_file = tmp.path().join("input.pdf");
+    /// Sets the path of `dvisvgm`.
+    ///
+    /// Only used for SVG output. If not set, will look for `dvisvgm` on the current `PATH`.
+    pub fn dvisvgm_path<P: Into<path::PathBuf>>(&mut self, dvisvgm_path: P) -> &mut Self {
+        self.dvisvgm_path = dvisvgm_path.into();
+        self
+    }
 
-        let mut texinputs = OsString::new();
-        for input in &self.texinputs {
-            texinputs.push(":");
-            texinputs.push(input.as_os_str());
-        }
+    /// Sets the TeX engine.
+    ///
+    /// If not set, XeLaTeX is used.
+    pub fn engine(&mut self, engine: TexEngine) -> &mut Self {
+        self.engine = engine;
+        self
+    }
 
-        fs::write(&input_file, &self.source).map_err(RenderingError::WriteInputFile)?;
+    /// Sets the output format.
+    ///
+    /// If not set, a PDF is rendered.
+    pub fn output_format(&mut self, output_format: OutputFormat) -> &mut Self {
+        self.output_format = output_format;
+        self
+    }
 
-        let mut cmd = process::Command::new(&self.latex_mk_path);
-        cmd.args(&[
-            "-interaction=nonstopmode",
-            "-halt-on-error",
-            "-file-line-error",
-            "-pdf",
-        ]);
+    /// Enables bibliography processing.
+    ///
+    /// `latexmk` runs `backend` along with the TeX engine as often as required. Rendering fails
+    /// with `RenderingError::BibliographyError` if the tool reports an error, and with
+    /// `RenderingError::UndefinedCitations` if any cited key is missing from the bibliography.
+    pub fn bibliography(&mut self, backend: BibBackend) -> &mut Self {
+        self.bibliography = Some(backend);
+        self
+    }
 
-        if self.use_xelatex {
-            cmd.arg("-xelatex");
-        }
+    /// Sets the shell escape policy.
+    ///
+    /// If not set, shell escape is disabled. Only enable shell escape for trusted documents, it
+    /// is always disabled for untrusted input.
+    pub fn shell_escape(&mut self, shell_escape: ShellEscape) -> &mut Self {
+        self.shell_escape = shell_escape;
+        self
+    }
 
-        if !self.allow_shell_escape {
-            cmd.arg("-no-shell-escape");
-        }
+    /// Sets the commands allowed in restricted shell escape mode.
+    ///
+    /// Replaces the allowlist of the TeX installation. Commands are given by their name, e.g.
+    /// `pygmentize`; names containing a `,` are invalid and ignored.
+    pub fn shell_escape_commands<I, S>(&mut self, commands: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.shell_escape_commands = Some(
+            commands
+                .into_iter()
+                .map(Into::into)
+                .filter(|command| !command.is_empty() && !command.contains(','))
+                .collect(),
+        );
+        self
+    }
 
-        cmd.arg(&input_file);
+    /// Sets whether the input is untrusted.
+    ///
+    /// Untrusted input is rendered with shell escape disabled, no matter the other settings, and
+    /// with paranoid kpathsea settings (`openin_any=p`, `openout_any=p`): absolute paths and
+    /// paths leading upwards through `..` cannot be read or written, neither can hidden files.
+    /// Files on the search paths remain accessible. `latexmk` runs with an environment cleared of
+    /// everything except `PATH` and variables set through `env`, and does not read any
+    /// `latexmkrc` files besides one set through `latexmkrc`.
+    ///
+    /// Sources are scanned for constructs that run shell commands or Lua code, like `\write18`
+    /// or `\directlua`, before rendering; any such document is refused with
+    /// `RenderingError::ForbiddenConstruct`. Refused file accesses are reported as
+    /// `RenderingError::ForbiddenFileAccess`.
+    ///
+    /// LuaLaTeX cannot contain untrusted input, as Lua code accesses files without kpathsea's
+    /// restrictions and scanning cannot find every way to run it. Rendering untrusted input with
+    /// `TexEngine::LuaLatex` fails with `RenderingError::InvalidOption`, as does rendering it with
+    /// the Tectonic backend, which ignores kpathsea's settings.
+    pub fn untrusted(&mut self, untrusted: bool) -> &mut Self {
+        self.untrusted = untrusted;
+        self
+    }
 
-        cmd.env("TEXINPUTS", texinputs);
-        cmd.current_dir(tmp.path());
+    /// Sets whether to produce reproducible output.
+    ///
+    /// Reproducible renderings of the same input are byte-identical. Tools are run with
+    /// `SOURCE_DATE_EPOCH` and `FORCE_SOURCE_DATE=1`, so all embedded dates, including `\today`,
+    /// are replaced by the source date. pdfTeX and LuaTeX additionally omit the trailer ID and
+    /// dates from the PDF, XeTeX's driver derives both from the source date.
+    ///
+    /// The source date is the Unix epoch, unless set through `source_date`.
+    pub fn reproducible(&mut self, reproducible: bool) -> &mut Self {
+        self.source_date = if reproducible {
+            Some(self.source_date.unwrap_or(SystemTime::UNIX_EPOCH))
+        } else {
+            None
+        };
+        self
+    }
 
-        let output = cmd.output().map_err(RenderingError::RunError)?;
+    /// Sets the date to embed into the output, enabling reproducible mode.
+    ///
+    /// See `reproducible` for details.
+    pub fn source_date(&mut self, date: SystemTime) -> &mut Self {
+        self.source_date = Some(date);
+        self
+    }
 
-        if !output.status.success() {
-            // latexmk failed,
-            return Err(RenderingError::LatexError {
-                status: output.status.code(),
-                stdout: output.stdout,
-                stderr: output.stderr,
-            });
-        }
+    /// Sets whether to write SyncTeX data, mapping positions in the output to the source.
+    ///
+    /// The SyncTeX file `<jobname>.synctex.gz` is returned among the artifacts and can be parsed
+    /// with `synctex::SyncTex::parse`.
+    pub fn synctex(&mut self, synctex: bool) -> &mut Self {
+        self.synctex = synctex;
+        self
+    }
 
-        fs::read(output_file).map_err(RenderingError::ReadOutputFile)
+    /// Sets an environment variable for all spawned processes.
+    ///
+    /// Variables controlled by `TexRender` itself, like kpathsea's `openin_any` or the search
+    /// paths, cannot be set, nor their program-specific variants like `openin_any.xelatex`;
+    /// rendering fails with `RenderingError::InvalidOption` if they are.
+    pub fn env<K: Into<OsString>, V: Into<OsString>>(&mut self, key: K, value: V) -> &mut Self {
+        self.env.push((key.into(), Some(value.into())));
+        self
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::{RenderingError, TexRender};
+    /// Removes an environment variable from the environment of all spawned processes.
+    ///
+    /// Subject to the same restrictions as `env`.
+    pub fn env_remove<K: Into<OsString>>(&mut self, key: K) -> &mut Self {
+        self.env.push((key.into(), None));
+        self
+    }
 
-    #[test]
-    fn render_example_tex() {
-        let doc = r"
-        \documentclass{article}
-        \begin{document}
-        hello, world.
-        \end{document}
-        ";
+    /// Sets whether spawned processes inherit the environment of the current process.
+    ///
+    /// If disabled, only `PATH` is inherited, along with variables set through `env`. Untrusted
+    /// input never inherits the environment.
+    pub fn inherit_env(&mut self, inherit_env: bool) -> &mut Self {
+        self.inherit_env = inherit_env;
+        self
+    }
 
-        let tex = TexRender::from_bytes(doc.into());
-        let _pdf = tex.render().unwrap();
+    /// Adds an argument to the backend's main command, e.g. `latexmk`.
+    ///
+    /// Only options known not to override settings controlled by `TexRender` are allowed, like
+    /// `-silent`, `-halt-on-error` or `-recorder`; values must be joined with `=`. Options
+    /// changing the shell escape policy, where output is written or the commands `latexmk` runs
+    /// are refused, as is `-recorder` for untrusted input. Rendering fails with
+    /// `RenderingError::InvalidOption` for any other argument.
+    pub fn add_arg<S: Into<OsString>>(&mut self, arg: S) -> &mut Self {
+        self.args.push(arg.into());
+        self
     }
 
-    #[test]
-    fn broken_tex_gives_correct_error() {
-        let doc = r"
-        \documentSOBROKENclass{article}
-        ";
+    /// Adds an argument passed to the TeX engine on every pass.
+    ///
+    /// Subject to the same restrictions as `add_arg`.
+    pub fn add_engine_arg<S: Into<OsString>>(&mut self, arg: S) -> &mut Self {
+        self.engine_args.push(arg.into());
+        self
+    }
 
-        let tex = TexRender::from_bytes(doc.into());
+    /// Sets the contents of an additional `latexmkrc`, e.g. holding custom dependency rules.
+    ///
+    /// Read after all other rc files, and even for untrusted input, which ignores all other rc
+    /// files. Only supported by the `latexmk` backend.
+    ///
+    /// The rc file is Perl code run by `latexmk` and cannot be validated. It must never contain
+    /// untrusted data.
+    pub fn latexmkrc<S: Into<String>>(&mut self, latexmkrc: S) -> &mut Self {
+        self.latexmkrc = Some(latexmkrc.into());
+        self
+    }
 
-        match tex.render() {
-            Err(RenderingError::LatexError { .. }) => (),
+    /// Requests an intermediate file to be returned along with the output.
+    ///
+    /// `artifact` is either a filename, e.g. `input.aux`, or an extension including the leading
+    /// dot, e.g. `.aux` or `.synctex.gz`, selecting all files ending in it. Only files next to the
+    /// main file are considered, requested files that were not produced are skipped.
+    pub fn add_artifact<S: Into<String>>(&mut self, artifact: S) -> &mut Self {
+        self.artifacts.push(artifact.into());
+        self
+    }
+
+    /// Sets a persistent build directory.
+    ///
+    /// Intermediate files like `.aux`, `.toc` or `.fdb_latexmk` are kept in the build directory
+    /// between renderings, so `latexmk` only reruns the passes that are actually needed. The
+    /// directory is created if it does not exist.
+    ///
+    /// A build directory must not be used by more than one rendering at the same time.
+    pub fn build_dir<P: Into<path::PathBuf>>(&mut self, build_dir: P) -> &mut Self {
+        self.build_dir = Some(build_dir.into());
+        self
+    }
+
+    /// Sets a timeout for rendering.
+    ///
+    /// If rendering takes longer than `timeout`, `latexmk` and all processes started by it are
+    /// killed and `RenderingError::Timeout` is returned. There is no timeout by default.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sets a cancellation token.
+    ///
+    /// Cancelling the token aborts any rendering in progress, killing `latexmk` and all processes
+    /// started by it. The aborted rendering returns `RenderingError::Cancelled`.
+    pub fn cancellation_token(&mut self, token: CancellationToken) -> &mut Self {
+        self.cancellation_token = Some(token);
+        self
+    }
+
+    /// Registers a callback receiving progress events while rendering.
+    ///
+    /// Events are derived from the output of `latexmk` and the TeX engine as it is printed, see
+    /// `events::RenderEvent`. The callback is called from background threads, but never
+    /// concurrently. To receive events elsewhere, send them through a channel:
+    ///
+    /// ```rust,no_run
+    /// # use texrender::TexRender;
+    /// let (sender, receiver) = std::sync::mpsc::channel();
+    /// let mut tex = TexRender::from_bytes(b"...".to_vec());
+    /// tex.on_event(move |event| {
+    ///     let _ = sender.send(event.clone());
+    /// });
+    /// ```
+    ///
+    /// Renderings answered from the cache report no events.
+    pub fn on_event<F>(&mut self, callback: F) -> &mut Self
+    where
+        F: Fn(&RenderEvent) + Send + Sync + 'static,
+    {
+        self.on_event = Some(EventHandler(Arc::new(callback)));
+        self
+    }
+
+    /// Limits the CPU time of every process spawned while rendering.
+    ///
+    /// The limit applies to `latexmk` and each engine pass individually, it is rounded up to whole
+    /// seconds. Exceeding it aborts the rendering with `RenderingError::ResourceLimitExceeded`.
+    /// Only supported on Unix.
+    pub fn cpu_time_limit(&mut self, limit: Duration) -> &mut Self {
+        self.limits.cpu_time = Some(limit);
+        self
+    }
+
+    /// Limits the address space of every process spawned while rendering, in bytes.
+    ///
+    /// Engines allocate their memory up front according to `texmf.cnf`, the limit must leave
+    /// room for that. Running out of memory is detected from the error messages printed. Only
+    /// supported on Unix.
+    pub fn memory_limit(&mut self, bytes: u64) -> &mut Self {
+        self.limits.address_space = Some(bytes);
+        self
+    }
+
+    /// Limits the size of every file written while rendering, in bytes.
+    ///
+    /// Only supported on Unix.
+    pub fn file_size_limit(&mut self, bytes: u64) -> &mut Self {
+        self.limits.file_size = Some(bytes);
+        self
+    }
+
+    /// Limits the number of files in the build directory, including sources.
+    ///
+    /// The build directory is watched while rendering; all processes are killed as soon as the
+    /// limit is exceeded.
+    pub fn file_count_limit(&mut self, files: usize) -> &mut Self {
+        self.limits.files = Some(files);
+        self
+    }
+
+    /// Sets a cache for rendering results.
+    ///
+    /// Before rendering, a key is computed by hashing the source, all assets, the files in all
+    /// search path folders, TeX-related variables of the inherited environment and the rendering
+    /// options. If the cache holds a result for the key, it is returned without running
+    /// `latexmk`; its `passes` will be zero.
+    ///
+    /// Files in folders added through `add_texinput` or `add_search_path` are not read, they are
+    /// keyed by path, size and modification time.
+    pub fn cache(&mut self, cache: RenderCache) -> &mut Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Adds a package that `preflight` checks to be installed, e.g. `hyperref`.
+    pub fn require_package<S: AsRef<str>>(&mut self, name: S) -> &mut Self {
+        self.required_files.push(format!("{}.sty", name.as_ref()));
+        self
+    }
+
+    /// Adds a document class that `preflight` checks to be installed, e.g. `scrartcl`.
+    pub fn require_class<S: AsRef<str>>(&mut self, name: S) -> &mut Self {
+        self.required_files.push(format!("{}.cls", name.as_ref()));
+        self
+    }
+
+    /// Computes the cache key for the current configuration.
+    fn cache_key(&self) -> io::Result<String> {
+        let mut key = cache::KeyBuilder::new();
+        key.bytes(self.main.to_string_lossy().as_bytes());
+        for (path, contents) in &self.sources {
+            key.bytes(path.to_string_lossy().as_bytes()).bytes(contents);
+        }
+
+        // The assets dir is part of the search paths, but located at a random path. Only its
+        // contents are hashed, which `KeyBuilder::dir` does regardless of location.
+        let assets = self.assets_dir.as_ref().map(tempdir::TempDir::path);
+        self.search_paths.hash(&mut key, assets)?;
+
+        key.bytes(self.backend.cache_id().as_bytes())
+            .bytes(self.dvisvgm_path.to_string_lossy().as_bytes())
+            .bytes(self.engine.latexmk_arg().as_bytes())
+            .bytes(format!("{:?}", self.output_format).as_bytes())
+            .bytes(format!("{:?}", self.bibliography).as_bytes())
+            .bytes(&[self.untrusted as u8])
+            .bytes(format!("{:?}", self.source_date).as_bytes())
+            .bytes(&[self.synctex as u8])
+            .bytes(format!("{:?}", self.env).as_bytes())
+            .bytes(&[self.inherit_env as u8])
+            .bytes(format!("{:?}", self.inherited_env(env::vars_os())).as_bytes())
+            .bytes(format!("{:?}", self.args).as_bytes())
+            .bytes(format!("{:?}", self.engine_args).as_bytes())
+            .bytes(format!("{:?}", self.latexmkrc).as_bytes())
+            .bytes(format!("{:?}", self.artifacts).as_bytes())
+            .bytes(format!("{:?}", self.shell_escape).as_bytes())
+            .bytes(format!("{:?}", self.shell_escape_commands).as_bytes());
+
+        Ok(key.finish())
+    }
+
+    /// Picks the variables of the parent environment `vars` passed on to commands that influence
+    /// the output, sorted by name.
+    ///
+    /// Variables overridden by `TexRender` are left out, as are all of them if the environment is
+    /// not inherited.
+    fn inherited_env<I>(&self, vars: I) -> Vec<(OsString, OsString)>
+    where
+        I: IntoIterator<Item = (OsString, OsString)>,
+    {
+        if self.untrusted || !self.inherit_env {
+            return Vec::new();
+        }
+
+        let search_paths = self.search_paths.env();
+        let overridden = |key: &OsStr| {
+            self.env.iter().any(|(name, _)| name == key)
+                || search_paths.iter().any(|(name, _)| OsStr::new(name) == key)
+                || (self.source_date.is_some()
+                    && (key == "SOURCE_DATE_EPOCH" || key == "FORCE_SOURCE_DATE"))
+        };
+
+        let mut inherited: Vec<_> = vars
+            .into_iter()
+            .filter(|(key, _)| {
+                let name = key.to_string_lossy();
+                KEYED_ENV_PREFIXES
+                    .iter()
+                    .any(|prefix| name.starts_with(prefix))
+                    && !overridden(key)
+            })
+            .collect();
+        inherited.sort();
+        inherited
+    }
+
+    /// Returns the conditions to run a command inside `build_dir` under.
+    fn supervision<'a>(
+        &'a self,
+        timeout: Option<Duration>,
+        build_dir: &'a path::Path,
+        events: Option<&'a EventParser>,
+    ) -> runner::Supervision<'a> {
+        runner::Supervision {
+            timeout,
+            events,
+            cancel: self.cancellation_token.as_ref(),
+            limits: if self.limits.is_unlimited() {
+                None
+            } else {
+                Some((&self.limits, build_dir))
+            },
+        }
+    }
+
+    /// Returns how much of the timeout is left for a rendering started at `started`.
+    fn remaining_time(&self, started: Instant) -> Option<Duration> {
+        self.timeout
+            .map(|timeout| timeout.saturating_sub(started.elapsed()))
+    }
+
+    /// Reports timeouts of follow-up commands with the configured timeout.
+    fn restore_timeout(&self, err: RenderingError) -> RenderingError {
+        match (err, self.timeout) {
+            (RenderingError::Timeout(_), Some(timeout)) => RenderingError::Timeout(timeout),
+            (err, _) => err,
+        }
+    }
+
+    /// Prepares the directory to render in.
+    fn prepare_build_dir(&self) -> Result<BuildDir, RenderingError> {
+        match self.build_dir {
+            Some(ref path) => {
+                fs::create_dir_all(path).map_err(RenderingError::BuildDirCreation)?;
+                Ok(BuildDir::Persistent(path.clone()))
+            }
+            None => tempdir::TempDir::new("texrender")
+                .map(BuildDir::Temporary)
+                .map_err(RenderingError::TempdirCreation),
+        }
+    }
+
+    /// Returns the extension of the file the backend produces.
+    fn backend_output_extension(&self) -> &'static str {
+        match (self.output_format, self.engine) {
+            (OutputFormat::Pdf, _) => "pdf",
+            (OutputFormat::PostScript, _) => "ps",
+            (_, TexEngine::XeLatex) => "xdv",
+            (_, _) => "dvi",
+        }
+    }
+
+    /// Builds the command converting backend output to the output format, if required.
+    ///
+    /// Removes pages left over from previous conversions in `work_dir`.
+    fn prepare_conversion(
+        &self,
+        work_dir: &path::Path,
+    ) -> Result<Option<process::Command>, RenderingError> {
+        if self.output_format != OutputFormat::Svg || self.backend.produces_svg() {
+            return Ok(None);
+        }
+
+        for name in self
+            .output_files(work_dir)
+            .map_err(RenderingError::ReadOutputFile)?
+        {
+            fs::remove_file(work_dir.join(name)).map_err(RenderingError::ReadOutputFile)?;
+        }
+
+        let mut cmd = process::Command::new(&self.dvisvgm_path);
+        cmd.args(["--page=1-", "--no-fonts"]);
+        cmd.arg(format!("--output={}-%p.svg", self.jobname()));
+        cmd.arg(format!(
+            "{}.{}",
+            self.jobname(),
+            self.backend_output_extension()
+        ));
+        Ok(Some(self.prepare_command(cmd, work_dir)))
+    }
+
+    /// Lists the files of the final output inside `work_dir`, in order.
+    fn output_files(&self, work_dir: &path::Path) -> io::Result<Vec<String>> {
+        let jobname = self.jobname();
+        if self.output_format != OutputFormat::Svg {
+            return Ok(vec![format!(
+                "{}.{}",
+                jobname,
+                self.backend_output_extension()
+            )]);
+        }
+
+        let mut pages = Vec::new();
+        for entry in fs::read_dir(work_dir)? {
+            if let Ok(name) = entry?.file_name().into_string() {
+                let page = name
+                    .strip_prefix(jobname.as_str())
+                    .and_then(|rest| rest.strip_prefix('-'))
+                    .and_then(|rest| rest.strip_suffix(".svg"))
+                    .and_then(|page| page.parse::<u32>().ok());
+                if let Some(page) = page {
+                    pages.push((page, name));
+                }
+            }
+        }
+        pages.sort();
+
+        Ok(pages.into_iter().map(|(_, name)| name).collect())
+    }
+
+    /// Lists the requested artifacts inside `work_dir`, sorted by name.
+    ///
+    /// The SyncTeX file is always included if enabled.
+    fn artifact_files(&self, work_dir: &path::Path) -> io::Result<Vec<String>> {
+        if self.artifacts.is_empty() && !self.synctex {
+            return Ok(Vec::new());
+        }
+
+        let synctex_file = format!("{}.synctex.gz", self.jobname());
+
+        let mut names = Vec::new();
+        for entry in fs::read_dir(work_dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            if let Ok(name) = entry.file_name().into_string() {
+                let requested = (self.synctex && name == synctex_file)
+                    || self.artifacts.iter().any(|artifact| {
+                        if artifact.starts_with('.') {
+                            !name.starts_with('.') && name.ends_with(artifact.as_str())
+                        } else {
+                            name == *artifact
+                        }
+                    });
+                if requested {
+                    names.push(name);
+                }
+            }
+        }
+        names.sort();
+
+        Ok(names)
+    }
+
+    /// Returns the jobname, which determines the names of all output files.
+    fn jobname(&self) -> String {
+        self.main
+            .file_stem()
+            .expect("main file has no filename")
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Returns the directory `latexmk` runs in, which is the one containing the main file.
+    fn work_dir(&self, build_dir: &path::Path) -> path::PathBuf {
+        match self.main.parent() {
+            Some(parent) => build_dir.join(parent),
+            None => build_dir.to_owned(),
+        }
+    }
+
+    /// Writes all sources into the build directory.
+    ///
+    /// Unchanged files are left untouched, keeping their timestamps for `latexmk`.
+    fn write_sources(&self, build_dir: &path::Path) -> Result<(), RenderingError> {
+        for (rel_path, contents) in &self.sources {
+            let path = build_dir.join(rel_path);
+            if fs::read(&path).ok().as_ref() == Some(contents) {
+                continue;
+            }
+
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).map_err(RenderingError::WriteInputFile)?;
+            }
+            fs::write(&path, contents).map_err(RenderingError::WriteInputFile)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes all sources into the build directory asynchronously.
+    #[cfg(feature = "tokio")]
+    async fn write_sources_async(&self, build_dir: &path::Path) -> Result<(), RenderingError> {
+        for (rel_path, contents) in &self.sources {
+            let path = build_dir.join(rel_path);
+            if tokio::fs::read(&path).await.ok().as_ref() == Some(contents) {
+                continue;
+            }
+
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent)
+                    .await
+                    .map_err(RenderingError::WriteInputFile)?;
+            }
+            tokio::fs::write(&path, contents)
+                .await
+                .map_err(RenderingError::WriteInputFile)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the shell escape policy in effect, which is always disabled for untrusted input.
+    fn effective_shell_escape(&self) -> ShellEscape {
+        if self.untrusted {
+            ShellEscape::Disabled
+        } else {
+            self.shell_escape
+        }
+    }
+
+    /// Starts a backend run rendering the main file inside `build_dir`.
+    fn start_backend(&self, build_dir: &path::Path) -> Result<Box<dyn BackendRun>, RenderingError> {
+        let jobname = self.jobname();
+        let output_file = format!("{}.{}", jobname, self.backend_output_extension());
+        self.backend.start(&RenderJob {
+            project_dir: build_dir,
+            work_dir: &self.work_dir(build_dir),
+            assets_dir: self.assets_dir.as_ref().map(tempdir::TempDir::path),
+            main_file: self.main.file_name().expect("main file has no filename"),
+            jobname: &jobname,
+            output_file: &output_file,
+            engine: self.engine,
+            output_format: self.output_format,
+            shell_escape: self.effective_shell_escape(),
+            bibliography: self.bibliography,
+            untrusted: self.untrusted,
+            reproducible: self.source_date.is_some(),
+            synctex: self.synctex,
+            args: &self.args,
+            engine_args: &self.engine_args,
+            latexmkrc: self.latexmkrc.as_deref(),
+        })
+    }
+
+    /// Sets up the environment of a command and makes it run inside `work_dir`.
+    fn prepare_command(
+        &self,
+        mut cmd: process::Command,
+        work_dir: &path::Path,
+    ) -> process::Command {
+        // Clearing the environment must not drop variables set by the backend.
+        let backend_env: Vec<(OsString, Option<OsString>)> = cmd
+            .get_envs()
+            .map(|(key, value)| (key.to_owned(), value.map(OsStr::to_owned)))
+            .collect();
+        if self.untrusted || !self.inherit_env {
+            cmd.env_clear();
+            if let Some(path) = env::var_os("PATH") {
+                cmd.env("PATH", path);
+            }
+        }
+        for (key, value) in backend_env.iter().chain(&self.env) {
+            match value {
+                Some(value) => cmd.env(key, value),
+                None => cmd.env_remove(key),
+            };
+        }
+
+        if self.untrusted {
+            cmd.env("openin_any", "p")
+                .env("openout_any", "p")
+                .env("shell_escape", "f");
+        }
+
+        if self.effective_shell_escape() == ShellEscape::Restricted {
+            if let Some(ref commands) = self.shell_escape_commands {
+                cmd.env("shell_escape_commands", commands.join(","));
+            }
+        }
+
+        if let Some(date) = self.source_date {
+            let epoch = date
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|since| since.as_secs())
+                .unwrap_or(0);
+            cmd.env("SOURCE_DATE_EPOCH", epoch.to_string())
+                .env("FORCE_SOURCE_DATE", "1");
+        }
+
+        cmd.envs(self.search_paths.env());
+        cmd.current_dir(work_dir);
+        cmd
+    }
+
+    /// Builds the first command of the backend to render the main file inside `build_dir`.
+    #[cfg(test)]
+    fn command(&self, build_dir: &path::Path) -> Result<process::Command, RenderingError> {
+        let cmd = self
+            .start_backend(build_dir)?
+            .next_command(None)?
+            .expect("backend runs no commands");
+        Ok(self.prepare_command(cmd, &self.work_dir(build_dir)))
+    }
+
+    /// Refuses environment variables, arguments and a `latexmkrc` that would override settings
+    /// controlled by `TexRender`, as well as engines unable to contain untrusted input.
+    fn check_options(&self) -> Result<(), RenderingError> {
+        // Lua code reads and writes files through its own `io` and `os` libraries, ignoring
+        // kpathsea's restrictions, and can be run without a recognizable `\directlua`.
+        if self.untrusted && self.engine == TexEngine::LuaLatex {
+            return Err(RenderingError::InvalidOption(
+                "untrusted input cannot be rendered with LuaLaTeX".to_owned(),
+            ));
+        }
+
+        for (key, _) in &self.env {
+            if is_protected_env(key) {
+                return Err(RenderingError::InvalidOption(format!(
+                    "environment variable {} is controlled by texrender",
+                    key.to_string_lossy()
+                )));
+            }
+        }
+
+        for arg in self.args.iter().chain(&self.engine_args) {
+            let arg = arg.to_string_lossy();
+            let name = match arg.strip_prefix('-') {
+                Some(option) => option
+                    .trim_start_matches('-')
+                    .split('=')
+                    .next()
+                    .unwrap_or(""),
+                None => {
+                    return Err(RenderingError::InvalidOption(format!(
+                        "argument {} is not an option",
+                        arg
+                    )))
+                }
+            };
+            let trusted = !self.untrusted && TRUSTED_ARGS.contains(&name);
+            if !ALLOWED_ARGS.contains(&name) && !trusted {
+                return Err(RenderingError::InvalidOption(format!(
+                    "argument {} is not an allowed option",
+                    arg
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Refuses untrusted sources containing forbidden constructs.
+    fn check_sources(&self) -> Result<(), RenderingError> {
+        if !self.untrusted {
+            return Ok(());
+        }
+
+        for (file, contents) in &self.sources {
+            if let Some((line, construct)) = untrusted::find_forbidden_construct(contents) {
+                return Err(RenderingError::ForbiddenConstruct {
+                    file: file.clone(),
+                    line,
+                    construct,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Turns file accesses refused for untrusted input into a
+    /// `RenderingError::ForbiddenFileAccess`.
+    fn check_file_access(&self, output: &process::Output) -> Result<(), RenderingError> {
+        if !self.untrusted {
+            return Ok(());
+        }
+
+        let mut paths = untrusted::refused_accesses(&output.stdout);
+        for path in untrusted::refused_accesses(&output.stderr) {
+            if !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+
+        if paths.is_empty() {
+            return Ok(());
+        }
+
+        Err(RenderingError::ForbiddenFileAccess(paths))
+    }
+
+    /// Turns errors in the bibliography tool's log into a `RenderingError::BibliographyError`.
+    fn check_bibliography(&self, blg: &[u8]) -> Result<(), RenderingError> {
+        let backend = match self.bibliography {
+            Some(backend) => backend,
+            None => return Ok(()),
+        };
+
+        let messages = diagnostics::bibliography_errors(blg);
+        if messages.is_empty() {
+            return Ok(());
+        }
+
+        Err(RenderingError::BibliographyError { backend, messages })
+    }
+
+    /// Turns undefined citations in the LaTeX log into a `RenderingError::UndefinedCitations`.
+    fn check_citations(&self, log: &[u8]) -> Result<(), RenderingError> {
+        if self.bibliography.is_none() {
+            return Ok(());
+        }
+
+        let mut keys: Vec<String> = Vec::new();
+        for diag in diagnostics::parse_log(log) {
+            if let Some(key) = diag.undefined_citation() {
+                if !keys.iter().any(|k| k == key) {
+                    keys.push(key.to_owned());
+                }
+            }
+        }
+
+        if keys.is_empty() {
+            return Ok(());
+        }
+
+        Err(RenderingError::UndefinedCitations(keys))
+    }
+
+    /// Checks that everything needed for rendering is installed.
+    ///
+    /// Looks up the programs the backend runs, along with `dvisvgm` for SVG output, on the `PATH`
+    /// spawned processes see, and queries their versions. Packages and classes added through
+    /// `require_package` and `require_class` are looked up with `kpsewhich`, using the search
+    /// paths of this rendering. Nothing is rendered.
+    ///
+    /// Fails only if the options are invalid or `kpsewhich` could not be run; problems with the
+    /// installation are listed in the returned report instead.
+    pub fn preflight(&self) -> Result<toolchain::PreflightReport, RenderingError> {
+        self.check_options()?;
+
+        let mut programs =
+            self.backend
+                .programs(self.engine, self.output_format, self.bibliography);
+        if self.output_format == OutputFormat::Svg && !self.backend.produces_svg() {
+            programs.push(self.dvisvgm_path.clone());
+        }
+        if !self.required_files.is_empty() {
+            programs.push("kpsewhich".into());
+        }
+
+        let search_path = match self.env.iter().rev().find(|(key, _)| key == "PATH") {
+            Some((_, value)) => value.clone(),
+            None => env::var_os("PATH"),
+        };
+        let toolchain = toolchain::Toolchain::probe(&programs, search_path.as_deref());
+
+        let missing_files = match toolchain.tool("kpsewhich") {
+            Some(kpsewhich) if !self.required_files.is_empty() => {
+                let cmd =
+                    self.prepare_command(process::Command::new(&kpsewhich.path), &env::temp_dir());
+                toolchain::missing_files(cmd, &self.required_files)?
+            }
+            _ => Vec::new(),
+        };
+
+        Ok(toolchain::PreflightReport {
+            missing_programs: toolchain.missing.clone(),
+            missing_files,
+            toolchain,
+        })
+    }
+
+    /// Creates a human-readable report of an error returned by a rendering.
+    ///
+    /// The report quotes the sources of this rendering, see `report::ErrorReport`.
+    pub fn report<'a>(&'a self, error: &'a RenderingError) -> report::ErrorReport<'a> {
+        let main_dir = self.main.parent().unwrap_or_else(|| path::Path::new(""));
+        let mut report = report::ErrorReport::new(error);
+        for (path, contents) in &self.sources {
+            report.source(path.strip_prefix(main_dir).unwrap_or(path), contents);
+        }
+        report
+    }
+
+    /// Renders the given source.
+    pub fn render(&self) -> Result<RenderOutput, RenderingError> {
+        self.render_finished()?.into_output()
+    }
+
+    /// Renders the given source into a file.
+    ///
+    /// The output is written to a temporary file next to `path` first, which is then moved into
+    /// place. `path` is thus either left untouched or replaced with the complete output. Only
+    /// supported for output formats producing a single file.
+    pub fn render_to_path<P: AsRef<path::Path>>(
+        &self,
+        path: P,
+    ) -> Result<RenderSummary, RenderingError> {
+        let path = path.as_ref();
+        let file_name = path.file_name().ok_or_else(|| {
+            RenderingError::WriteOutput(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "output path has no filename",
+            ))
+        })?;
+
+        let mut staging_name = OsString::from(".");
+        staging_name.push(file_name);
+        staging_name.push(format!(
+            ".texrender-{}-{}",
+            process::id(),
+            STAGING_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let staging = path.with_file_name(staging_name);
+
+        let result = fs::File::create(&staging)
+            .map_err(RenderingError::WriteOutput)
+            .and_then(|mut file| {
+                let summary = self.render_to_writer(&mut file)?;
+                file.sync_all().map_err(RenderingError::WriteOutput)?;
+                fs::rename(&staging, path).map_err(RenderingError::WriteOutput)?;
+                Ok(summary)
+            });
+
+        if result.is_err() {
+            let _ = fs::remove_file(&staging);
+        }
+
+        result
+    }
+
+    /// Renders the given source into a writer.
+    ///
+    /// The output is streamed from disk, it is never held in memory as a whole. Only supported
+    /// for output formats producing a single file.
+    pub fn render_to_writer<W: Write>(
+        &self,
+        mut writer: W,
+    ) -> Result<RenderSummary, RenderingError> {
+        if self.output_format == OutputFormat::Svg {
+            return Err(RenderingError::MultipleOutputFiles(self.output_format));
+        }
+
+        let mut finished = self.render_finished()?;
+        for (_, file) in &finished.files {
+            io::copy(&mut io::BufReader::new(file), &mut writer)
+                .map_err(RenderingError::WriteOutput)?;
+        }
+        writer.flush().map_err(RenderingError::WriteOutput)?;
+
+        let artifacts = read_files(std::mem::take(&mut finished.artifacts))?;
+        Ok(finished.summary(artifacts))
+    }
+
+    /// Renders the given source, consulting the cache if set.
+    fn render_finished(&self) -> Result<Finished, RenderingError> {
+        let cache = match self.cache {
+            Some(ref cache) => cache,
+            None => return self.build()?.open(),
+        };
+
+        let key = self.cache_key().map_err(RenderingError::Cache)?;
+        if let Some(hit) = cache.lookup(&key) {
+            return Ok(Finished::cached(hit));
+        }
+
+        let built = self.build()?;
+        cache
+            .insert(&key, &built.paths(), &built.artifact_paths(), &built.log)
+            .map_err(RenderingError::Cache)?;
+        built.open()
+    }
+
+    /// Runs the backend and any conversion, leaving the output in the build directory.
+    fn build(&self) -> Result<Built, RenderingError> {
+        self.check_options()?;
+        self.check_sources()?;
+        let build_dir = self.prepare_build_dir()?;
+        let work_dir = self.work_dir(build_dir.path());
+        let log_file = work_dir.join(format!("{}.log", self.jobname()));
+        let blg_file = work_dir.join(format!("{}.blg", self.jobname()));
+
+        self.write_sources(build_dir.path())?;
+
+        let started = Instant::now();
+        let events = self.on_event.clone().map(EventParser::new);
+        let mut run = self.start_backend(build_dir.path())?;
+        let mut previous: Option<process::Output> = None;
+        while let Some(cmd) = run.next_command(previous.as_ref())? {
+            let output = runner::run(
+                self.prepare_command(cmd, &work_dir),
+                &self.supervision(
+                    self.remaining_time(started),
+                    build_dir.path(),
+                    events.as_ref(),
+                ),
+            )
+            .map_err(|err| self.restore_timeout(err))?;
+
+            self.check_file_access(&output)?;
+            let failed = !output.status.success();
+            previous = Some(output);
+            if failed {
+                break;
+            }
+        }
+
+        let log = fs::read(log_file).unwrap_or_default();
+        let blg = if self.bibliography.is_some() {
+            fs::read(blg_file).unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+        self.check_outcome(previous, &log, &blg)?;
+
+        if let Some(cmd) = self.prepare_conversion(&work_dir)? {
+            let conversion = runner::run(
+                cmd,
+                &self.supervision(
+                    self.remaining_time(started),
+                    build_dir.path(),
+                    events.as_ref(),
+                ),
+            )
+            .map_err(|err| self.restore_timeout(err))?;
+            check_conversion_status(conversion)?;
+        }
+
+        Ok(Built {
+            files: self
+                .output_files(&work_dir)
+                .map_err(RenderingError::ReadOutputFile)?,
+            artifacts: self
+                .artifact_files(&work_dir)
+                .map_err(RenderingError::ReadOutputFile)?,
+            build_dir,
+            work_dir,
+            log,
+            passes: run.passes(),
+        })
+    }
+
+    /// Checks the outcome of a backend run, given the output of its last command.
+    fn check_outcome(
+        &self,
+        last: Option<process::Output>,
+        log: &[u8],
+        blg: &[u8],
+    ) -> Result<(), RenderingError> {
+        self.check_bibliography(blg)?;
+        if let Some(output) = last {
+            check_status(output, log)?;
+        }
+        self.check_citations(log)
+    }
+
+    /// Renders the given source asynchronously.
+    ///
+    /// Behaves like `render`, but uses non-blocking process and file IO. Dropping the returned
+    /// future kills `latexmk` and all processes started by it.
+    ///
+    /// Cache lookups, if a cache is set, are still performed synchronously.
+    #[cfg(feature = "tokio")]
+    pub async fn render_async(&self) -> Result<RenderOutput, RenderingError> {
+        let cache = match self.cache {
+            Some(ref cache) => cache,
+            None => return self.build_async().await?.open()?.into_output_async().await,
+        };
+
+        let key = self.cache_key().map_err(RenderingError::Cache)?;
+        if let Some(hit) = cache.lookup(&key) {
+            return Finished::cached(hit).into_output_async().await;
+        }
+
+        let built = self.build_async().await?;
+        cache
+            .insert(&key, &built.paths(), &built.artifact_paths(), &built.log)
+            .map_err(RenderingError::Cache)?;
+        built.open()?.into_output_async().await
+    }
+
+    /// Runs the backend and any conversion asynchronously.
+    #[cfg(feature = "tokio")]
+    async fn build_async(&self) -> Result<Built, RenderingError> {
+        self.check_options()?;
+        self.check_sources()?;
+        let build_dir = self.prepare_build_dir()?;
+        let work_dir = self.work_dir(build_dir.path());
+        let log_file = work_dir.join(format!("{}.log", self.jobname()));
+        let blg_file = work_dir.join(format!("{}.blg", self.jobname()));
+
+        self.write_sources_async(build_dir.path()).await?;
+
+        let started = Instant::now();
+        let events = self.on_event.clone().map(EventParser::new);
+        let mut run = self.start_backend(build_dir.path())?;
+        let mut previous: Option<process::Output> = None;
+        while let Some(cmd) = run.next_command(previous.as_ref())? {
+            let output = runner::run_async(
+                self.prepare_command(cmd, &work_dir),
+                &self.supervision(
+                    self.remaining_time(started),
+                    build_dir.path(),
+                    events.as_ref(),
+                ),
+            )
+            .await
+            .map_err(|err| self.restore_timeout(err))?;
+
+            self.check_file_access(&output)?;
+            let failed = !output.status.success();
+            previous = Some(output);
+            if failed {
+                break;
+            }
+        }
+
+        let log = tokio::fs::read(log_file).await.unwrap_or_default();
+        let blg = if self.bibliography.is_some() {
+            tokio::fs::read(blg_file).await.unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+        self.check_outcome(previous, &log, &blg)?;
+
+        if let Some(cmd) = self.prepare_conversion(&work_dir)? {
+            let conversion = runner::run_async(
+                cmd,
+                &self.supervision(
+                    self.remaining_time(started),
+                    build_dir.path(),
+                    events.as_ref(),
+                ),
+            )
+            .await
+            .map_err(|err| self.restore_timeout(err))?;
+            check_conversion_status(conversion)?;
+        }
+
+        Ok(Built {
+            files: self
+                .output_files(&work_dir)
+                .map_err(RenderingError::ReadOutputFile)?,
+            artifacts: self
+                .artifact_files(&work_dir)
+                .map_err(RenderingError::ReadOutputFile)?,
+            build_dir,
+            work_dir,
+            log,
+            passes: run.passes(),
+        })
+    }
+}
+
+/// A rendering whose output files are still inside the build directory.
+#[derive(Debug)]
+struct Built {
+    /// Build directory holding the output.
+    build_dir: BuildDir,
+    /// Directory inside the build directory the output files are located in.
+    work_dir: path::PathBuf,
+    /// Names of the output files, in order.
+    files: Vec<String>,
+    /// Names of the artifacts, in order.
+    artifacts: Vec<String>,
+    /// Contents of the LaTeX log.
+    log: Vec<u8>,
+    /// Number of engine passes.
+    passes: usize,
+}
+
+impl Built {
+    /// Returns names and full paths of the output files.
+    fn paths(&self) -> Vec<(String, path::PathBuf)> {
+        self.files
+            .iter()
+            .map(|name| (name.clone(), self.work_dir.join(name)))
+            .collect()
+    }
+
+    /// Returns names and full paths of the artifacts.
+    fn artifact_paths(&self) -> Vec<(String, path::PathBuf)> {
+        self.artifacts
+            .iter()
+            .map(|name| (name.clone(), self.work_dir.join(name)))
+            .collect()
+    }
+
+    /// Opens all output files and artifacts for reading.
+    fn open(self) -> Result<Finished, RenderingError> {
+        let open = |paths: Vec<(String, path::PathBuf)>| {
+            paths
+                .into_iter()
+                .map(|(name, path)| {
+                    fs::File::open(path)
+                        .map(|file| (name, file))
+                        .map_err(RenderingError::ReadOutputFile)
+                })
+                .collect::<Result<Vec<_>, _>>()
+        };
+
+        Ok(Finished {
+            files: open(self.paths())?,
+            artifacts: open(self.artifact_paths())?,
+            log: self.log,
+            passes: self.passes,
+            _build_dir: Some(self.build_dir),
+        })
+    }
+}
+
+/// A finished rendering, with its output files opened for reading.
+#[derive(Debug)]
+struct Finished {
+    /// Output files, in order.
+    files: Vec<(String, fs::File)>,
+    /// Artifacts, in order.
+    artifacts: Vec<(String, fs::File)>,
+    /// Contents of the LaTeX log.
+    log: Vec<u8>,
+    /// Number of engine passes.
+    passes: usize,
+    /// Keeps a temporary build directory alive until the output has been read.
+    _build_dir: Option<BuildDir>,
+}
+
+impl Finished {
+    /// Creates a finished rendering from a cache hit.
+    fn cached(hit: cache::CacheHit) -> Self {
+        Finished {
+            files: hit.files,
+            artifacts: hit.artifacts,
+            log: hit.log,
+            passes: 0,
+            _build_dir: None,
+        }
+    }
+
+    /// Summarizes the rendering, given its artifacts.
+    fn summary(&self, artifacts: Vec<OutputFile>) -> RenderSummary {
+        RenderSummary {
+            warnings: diagnostics::parse_log(&self.log)
+                .into_iter()
+                .filter(|diag| diag.severity == Severity::Warning)
+                .collect(),
+            pages: diagnostics::page_count(&self.log),
+            passes: self.passes,
+            artifacts,
+        }
+    }
+
+    /// Reads all output files and artifacts into memory.
+    fn into_output(mut self) -> Result<RenderOutput, RenderingError> {
+        let artifacts = read_files(std::mem::take(&mut self.artifacts))?;
+        let summary = self.summary(artifacts);
+        Ok(RenderOutput::new(read_files(self.files)?, summary))
+    }
+
+    /// Reads all output files and artifacts into memory asynchronously.
+    #[cfg(feature = "tokio")]
+    async fn into_output_async(mut self) -> Result<RenderOutput, RenderingError> {
+        let artifacts = read_files_async(std::mem::take(&mut self.artifacts)).await?;
+        let summary = self.summary(artifacts);
+        Ok(RenderOutput::new(
+            read_files_async(self.files).await?,
+            summary,
+        ))
+    }
+}
+
+/// Reads opened files into memory.
+fn read_files(files: Vec<(String, fs::File)>) -> Result<Vec<OutputFile>, RenderingError> {
+    let mut output = Vec::new();
+    for (name, mut file) in files {
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)
+            .map_err(RenderingError::ReadOutputFile)?;
+        output.push(OutputFile { name, data });
+    }
+
+    Ok(output)
+}
+
+/// Reads opened files into memory asynchronously.
+#[cfg(feature = "tokio")]
+async fn read_files_async(
+    files: Vec<(String, fs::File)>,
+) -> Result<Vec<OutputFile>, RenderingError> {
+    use tokio::io::AsyncReadExt;
+
+    let mut output = Vec::new();
+    for (name, file) in files {
+        let mut data = Vec::new();
+        tokio::fs::File::from_std(file)
+            .read_to_end(&mut data)
+            .await
+            .map_err(RenderingError::ReadOutputFile)?;
+        output.push(OutputFile { name, data });
+    }
+
+    Ok(output)
+}
+
+/// Checks that a path is relative and stays inside the project root.
+fn check_project_path(path: &path::Path) -> io::Result<()> {
+    let valid = path.components().all(|component| {
+        matches!(
+            component,
+            path::Component::Normal(_) | path::Component::CurDir
+        )
+    });
+
+    if !valid || path.file_name().is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid source path {}", path.display()),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Recursively reads all non-hidden files below `base.join(rel_dir)`.
+fn read_project_dir(
+    base: &path::Path,
+    rel_dir: &path::Path,
+    sources: &mut Vec<(path::PathBuf, Vec<u8>)>,
+) -> io::Result<()> {
+    for entry in fs::read_dir(base.join(rel_dir))? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+
+        let rel_path = rel_dir.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            read_project_dir(base, &rel_path, sources)?;
+        } else {
+            sources.push((rel_path, fs::read(entry.path())?));
+        }
+    }
+
+    Ok(())
+}
+
+/// Directory a rendering takes place in.
+#[derive(Debug)]
+enum BuildDir {
+    /// Temporary directory, removed after rendering.
+    Temporary(tempdir::TempDir),
+    /// User-supplied directory that is kept.
+    Persistent(path::PathBuf),
+}
+
+impl BuildDir {
+    /// Returns the path of the build directory.
+    fn path(&self) -> &path::Path {
+        match self {
+            BuildDir::Temporary(tmp) => tmp.path(),
+            BuildDir::Persistent(path) => path,
+        }
+    }
+}
+
+/// Checks whether an environment variable overrides a variable controlled by `TexRender`.
+///
+/// Besides the variables themselves, kpathsea reads program-specific variants like
+/// `openin_any.xelatex` or `TEXINPUTS_pdflatex`, which are refused as well.
+fn is_protected_env(key: &OsStr) -> bool {
+    let key = key.to_string_lossy();
+    let names = PROTECTED_ENV
+        .iter()
+        .copied()
+        .chain(SearchVariable::ALL.iter().map(|var| var.name()));
+
+    for name in names {
+        if let Some(rest) = key.strip_prefix(name) {
+            if rest.is_empty() || rest.starts_with('.') || rest.starts_with('_') {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// Turns an unsuccessful backend command into a `RenderingError::LatexError`.
+fn check_status(output: process::Output, log: &[u8]) -> Result<process::Output, RenderingError> {
+    if output.status.success() {
+        return Ok(output);
+    }
+
+    Err(RenderingError::LatexError {
+        status: output.status.code(),
+        stdout: output.stdout,
+        stderr: output.stderr,
+        diagnostics: diagnostics::parse_log(log),
+    })
+}
+
+/// Turns an unsuccessful conversion into a `RenderingError::ConversionError`.
+fn check_conversion_status(output: process::Output) -> Result<(), RenderingError> {
+    if output.status.success() {
+        return Ok(());
+    }
+
+    Err(RenderingError::ConversionError {
+        tool: "dvisvgm",
+        status: output.status.code(),
+        stderr: output.stderr,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        backend::{DirectEngine, Fake},
+        BibBackend, DefaultPath, OutputFormat, RenderCache, RenderingError, Resource,
+        SearchVariable, ShellEscape, TexEngine, TexRender,
+    };
+    use crate::{events::RenderEvent, synctex::SyncTex};
+    use std::ffi::{OsStr, OsString};
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn render_example_tex() {
+        let doc = r"
+        \documentclass{article}
+        \begin{document}
+        hello, world.
+        \end{document}
+        ";
+
+        let tex = TexRender::from_bytes(doc.into());
+        let output = tex.render().unwrap();
+        assert_eq!(output.files.len(), 1);
+        assert_eq!(output.files[0].name, "input.pdf");
+        assert!(output.files[0].data.starts_with(b"%PDF"));
+        assert_eq!(output.pages, Some(1));
+        assert!(output.passes >= 1);
+    }
+
+    #[test]
+    fn render_to_path_replaces_file() {
+        let doc = r"
+        \documentclass{article}
+        \begin{document}
+        hello, world.
+        \end{document}
+        ";
+
+        let out_dir = tempdir::TempDir::new("texrender-test").unwrap();
+        let out_file = out_dir.path().join("hello.pdf");
+        std::fs::write(&out_file, b"old").unwrap();
+
+        let summary = TexRender::from_bytes(doc.into())
+            .render_to_path(&out_file)
+            .unwrap();
+        assert_eq!(summary.pages, Some(1));
+        assert!(std::fs::read(&out_file).unwrap().starts_with(b"%PDF"));
+        assert_eq!(std::fs::read_dir(out_dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn render_to_writer_rejects_multiple_files() {
+        let mut tex = TexRender::from_bytes(Vec::new());
+        tex.output_format(OutputFormat::Svg);
+
+        let mut buf = Vec::new();
+        match tex.render_to_writer(&mut buf) {
+            Err(RenderingError::MultipleOutputFiles(OutputFormat::Svg)) => (),
+            other => panic!("expected multiple output files error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn sources_must_stay_inside_project() {
+        for path in &[
+            "/etc/passwd",
+            "../outside.tex",
+            "chapters/../../outside.tex",
+        ] {
+            let sources = vec![("main.tex", Vec::new()), (*path, Vec::new())];
+            assert!(TexRender::from_sources(sources, "main.tex").is_err());
+        }
+
+        let sources = vec![("main.tex", Vec::new())];
+        assert!(TexRender::from_sources(sources, "other.tex").is_err());
+    }
+
+    #[test]
+    fn main_file_selects_jobname_and_directory() {
+        let sources = vec![
+            ("book/thesis.tex", Vec::new()),
+            ("book/chapters/intro.tex", Vec::new()),
+        ];
+        let tex = TexRender::from_sources(sources, "book/thesis.tex").unwrap();
+
+        let cmd = tex.command(Path::new("/build")).unwrap();
+        assert_eq!(cmd.get_current_dir(), Some(Path::new("/build/book")));
+        assert_eq!(cmd.get_args().last(), Some(OsStr::new("thesis.tex")));
+        assert_eq!(
+            tex.output_files(Path::new("/build/book")).unwrap(),
+            ["thesis.pdf"]
+        );
+    }
+
+    #[test]
+    fn render_project_dir() {
+        let project = tempdir::TempDir::new("texrender-test").unwrap();
+        std::fs::create_dir(project.path().join("chapters")).unwrap();
+        std::fs::write(
+            project.path().join("thesis.tex"),
+            r"
+            \documentclass{report}
+            \begin{document}
+            \include{chapters/intro}
+            \end{document}
+            ",
+        )
+        .unwrap();
+        std::fs::write(
+            project.path().join("chapters/intro.tex"),
+            r"\chapter{Introduction} hello, world.",
+        )
+        .unwrap();
+
+        let tex = TexRender::from_dir(project.path(), "thesis.tex").unwrap();
+        let output = tex.render().unwrap();
+        assert_eq!(output.files[0].name, "thesis.pdf");
+        assert_eq!(output.pages, Some(1));
+    }
+
+    #[test]
+    fn persistent_build_dir_skips_passes() {
+        let doc = r"
+        \documentclass{article}
+        \begin{document}
+        \tableofcontents
+        \section{Hello}
+        hello, world.
+        \end{document}
+        ";
+
+        let build_dir = tempdir::TempDir::new("texrender-test").unwrap();
+        let mut tex = TexRender::from_bytes(doc.into());
+        tex.build_dir(build_dir.path());
+
+        let first = tex.render().unwrap();
+        assert!(first.passes >= 2);
+        assert!(build_dir.path().join("input.aux").exists());
+
+        let second = tex.render().unwrap();
+        assert_eq!(second.passes, 0);
+        assert_eq!(first.pages, second.pages);
+    }
+
+    #[test]
+    fn cache_key_covers_inputs() {
+        let mut tex = TexRender::from_bytes(b"hello".to_vec());
+        let initial = tex.cache_key().unwrap();
+        assert_eq!(
+            initial,
+            TexRender::from_bytes(b"hello".to_vec())
+                .cache_key()
+                .unwrap()
+        );
+
+        tex.engine(TexEngine::LuaLatex);
+        let lua = tex.cache_key().unwrap();
+        assert_ne!(initial, lua);
+
+        tex.add_asset_from_bytes("logo.svg", b"<svg/>").unwrap();
+        let with_asset = tex.cache_key().unwrap();
+        assert_ne!(lua, with_asset);
+
+        tex.add_asset_from_bytes("logo.svg", b"<svg></svg>")
+            .unwrap();
+        let changed_asset = tex.cache_key().unwrap();
+        assert_ne!(with_asset, changed_asset);
+
+        tex.dvisvgm_path("/opt/texlive/bin/dvisvgm");
+        assert_ne!(changed_asset, tex.cache_key().unwrap());
+
+        let mut other = TexRender::from_bytes(b"hello".to_vec());
+        other.engine(TexEngine::LuaLatex);
+        other
+            .add_asset_from_bytes("logo.svg", b"<svg></svg>")
+            .unwrap();
+        assert_eq!(changed_asset, other.cache_key().unwrap());
+    }
+
+    #[test]
+    fn cache_key_covers_inherited_env() {
+        let vars = || {
+            [
+                ("HOME", "/home/user"),
+                ("TEXMFHOME", "/texmf"),
+                ("TEXINPUTS.pdflatex", "/inputs//"),
+                ("TEXINPUTS", "/inputs//"),
+                ("SOURCE_DATE_EPOCH", "1"),
+            ]
+            .iter()
+            .map(|(key, value)| (OsString::from(key), OsString::from(value)))
+            .collect::<Vec<_>>()
+        };
+        let names = |tex: &TexRender| {
+            tex.inherited_env(vars())
+                .into_iter()
+                .map(|(key, _)| key.into_string().unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        let mut tex = TexRender::from_bytes(b"hello".to_vec());
+        assert_eq!(
+            names(&tex),
+            [
+                "SOURCE_DATE_EPOCH",
+                "TEXINPUTS",
+                "TEXINPUTS.pdflatex",
+                "TEXMFHOME"
+            ]
+        );
+
+        tex.env("TEXMFHOME", "/other")
+            .add_texinput("/project")
+            .source_date(SystemTime::UNIX_EPOCH);
+        assert_eq!(names(&tex), ["TEXINPUTS.pdflatex"]);
+
+        tex.inherit_env(false);
+        assert!(names(&tex).is_empty());
+    }
+
+    #[test]
+    fn cached_render_skips_latexmk() {
+        let doc = r"
+        \documentclass{article}
+        \begin{document}
+        hello, world.
+        \end{document}
+        ";
+
+        let cache_dir = tempdir::TempDir::new("texrender-test").unwrap();
+        let cache = RenderCache::open(cache_dir.path(), 10 * 1024 * 1024).unwrap();
+        let mut tex = TexRender::from_bytes(doc.into());
+        tex.cache(cache.clone());
+
+        let first = tex.render().unwrap();
+        assert!(first.passes >= 1);
+        assert_eq!(cache.entries().unwrap().len(), 1);
+
+        let cached = TexRender::from_bytes(doc.into())
+            .cache(cache)
+            .render()
+            .unwrap();
+        assert_eq!(first.files[0].data, cached.files[0].data);
+        assert_eq!(cached.passes, 0);
+    }
+
+    #[test]
+    fn infinite_loop_times_out() {
+        let doc = r"
+        \documentclass{article}
+        \def\recurse{\recurse}
+        \begin{document}
+        \recurse
+        \end{document}
+        ";
+
+        let mut tex = TexRender::from_bytes(doc.into());
+        tex.timeout(Duration::from_secs(5));
+
+        match tex.render() {
+            Err(RenderingError::Timeout(_)) => (),
+            other => panic!("expected timeout, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn infinite_loop_exceeds_cpu_time_limit() {
+        let doc = r"
+        \documentclass{article}
+        \def\recurse{\recurse}
+        \begin{document}
+        \recurse
+        \end{document}
+        ";
+
+        let mut tex = TexRender::from_bytes(doc.into());
+        tex.cpu_time_limit(Duration::from_secs(2))
+            .timeout(Duration::from_secs(30));
+
+        match tex.render() {
+            Err(RenderingError::ResourceLimitExceeded(Resource::CpuTime)) => (),
+            other => panic!("expected CPU time limit, got {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn render_example_tex_async() {
+        let doc = r"
+        \documentclass{article}
+        \begin{document}
+        hello, world.
+        \end{document}
+        ";
+
+        let mut tex = TexRender::from_bytes(doc.into());
+        tex.add_asset_from_bytes_async("unused.txt", b"asset")
+            .await
+            .unwrap();
+        let output = tex.render_async().await.unwrap();
+        assert!(output.files[0].data.starts_with(b"%PDF"));
+    }
+
+    fn command_args(tex: &TexRender) -> Vec<String> {
+        tex.command(Path::new("."))
+            .unwrap()
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn command_selects_engine() {
+        let mut tex = TexRender::from_bytes(Vec::new());
+        assert_eq!(
+            command_args(&tex),
+            [
+                "-interaction=nonstopmode",
+                "-halt-on-error",
+                "-file-line-error",
+                "-pdfxe",
+                "-no-shell-escape",
+                "input.tex"
+            ]
+        );
+
+        for (engine, arg) in &[
+            (TexEngine::PdfLatex, "-pdf"),
+            (TexEngine::XeLatex, "-pdfxe"),
+            (TexEngine::LuaLatex, "-pdflua"),
+        ] {
+            tex.engine(*engine);
+            let args = command_args(&tex);
+            assert_eq!(args[3], *arg);
+            assert_eq!(args.iter().filter(|a| a.starts_with("-pdf")).count(), 1);
+        }
+    }
+
+    #[test]
+    fn command_selects_output_format() {
+        let mut tex = TexRender::from_bytes(Vec::new());
+        tex.engine(TexEngine::LuaLatex)
+            .output_format(OutputFormat::PostScript);
+        assert_eq!(command_args(&tex)[3..5], ["-dvilua", "-ps"]);
+
+        tex.engine(TexEngine::XeLatex)
+            .output_format(OutputFormat::Svg);
+        assert_eq!(command_args(&tex)[3], "-xdv");
+        let build_dir = tempdir::TempDir::new("texrender-test").unwrap();
+        let conversion = tex.prepare_conversion(build_dir.path()).unwrap().unwrap();
+        assert_eq!(conversion.get_args().last(), Some(OsStr::new("input.xdv")));
+
+        tex.output_format(OutputFormat::PostScript);
+        match tex.command(Path::new(".")) {
+            Err(RenderingError::UnsupportedOutputFormat { .. }) => (),
+            other => panic!("expected unsupported output format, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn svg_output_has_one_file_per_page() {
+        let doc = r"
+        \documentclass{article}
+        \begin{document}
+        first page
+        \newpage
+        second page
+        \end{document}
+        ";
+
+        let mut tex = TexRender::from_bytes(doc.into());
+        tex.engine(TexEngine::PdfLatex)
+            .output_format(OutputFormat::Svg);
+        let output = tex.render().unwrap();
+
+        let names: Vec<_> = output.files.iter().map(|file| file.name.as_str()).collect();
+        assert_eq!(names, ["input-1.svg", "input-2.svg"]);
+    }
+
+    #[test]
+    fn command_sets_texinputs() {
+        let mut tex = TexRender::from_bytes(Vec::new());
+        tex.add_texinput("/foo").add_texinput("/bar");
+        let cmd = tex.command(Path::new(".")).unwrap();
+        let texinputs = cmd
+            .get_envs()
+            .find(|(key, _)| *key == OsStr::new("TEXINPUTS"))
+            .and_then(|(_, value)| value);
+        assert_eq!(texinputs, Some(OsStr::new(":/foo:/bar")));
+    }
+
+    #[test]
+    fn command_enables_bibliography() {
+        let mut tex = TexRender::from_bytes(Vec::new());
+        tex.add_texinput("/refs").bibliography(BibBackend::Biber);
+        let cmd = tex.command(Path::new(".")).unwrap();
+        assert!(cmd.get_args().any(|arg| arg == "-bibtex"));
+
+        for var in &["BIBINPUTS", "BSTINPUTS"] {
+            let value = cmd
+                .get_envs()
+                .find(|(key, _)| key == var)
+                .and_then(|(_, value)| value);
+            assert_eq!(value, Some(OsStr::new(":/refs")));
+        }
+    }
+
+    #[test]
+    fn render_with_bibliography() {
+        let doc = r"
+        \documentclass{article}
+        \begin{document}
+        As shown by \cite{knuth84}.
+        \bibliographystyle{plain}
+        \bibliography{refs}
+        \end{document}
+        ";
+
+        let mut tex = TexRender::from_bytes(doc.into());
+        tex.add_bib_database(
+            "refs.bib",
+            br"@book{knuth84, author={Donald E. Knuth}, title={The {\TeX}book}, year=1984}",
+        )
+        .unwrap();
+        tex.bibliography(BibBackend::Bibtex);
+
+        let output = tex.render().unwrap();
+        assert!(output.passes >= 2);
+        assert!(output
+            .warnings
+            .iter()
+            .all(|diag| diag.undefined_citation().is_none()));
+    }
+
+    #[test]
+    fn undefined_citation_fails_render() {
+        let doc = r"
+        \documentclass{article}
+        \begin{document}
+        As shown by \cite{knuth84} and \cite{missing}.
+        \bibliographystyle{plain}
+        \bibliography{refs}
+        \end{document}
+        ";
+
+        let mut tex = TexRender::from_bytes(doc.into());
+        tex.add_bib_database("refs.bib", b"@misc{knuth84, title={Other}}")
+            .unwrap();
+        tex.bibliography(BibBackend::Bibtex);
+
+        match tex.render() {
+            Err(RenderingError::UndefinedCitations(keys)) => assert_eq!(keys, ["missing"]),
+            other => panic!("expected undefined citations, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn command_sets_search_paths() {
+        let mut tex = TexRender::from_bytes(Vec::new());
+        tex.add_search_path_recursive(SearchVariable::OsFontDir, "/fonts")
+            .add_search_path(SearchVariable::LuaInputs, "/lua")
+            .default_search_path(SearchVariable::LuaInputs, DefaultPath::After);
+        let cmd = tex.command(Path::new(".")).unwrap();
+
+        let env = |name: &str| {
+            cmd.get_envs()
+                .find(|(key, _)| *key == OsStr::new(name))
+                .and_then(|(_, value)| value)
+        };
+        assert_eq!(env("OSFONTDIR"), Some(OsStr::new(":/fonts//")));
+        assert_eq!(env("LUAINPUTS"), Some(OsStr::new("/lua:")));
+        assert_eq!(env("TEXINPUTS"), None);
+    }
+
+    #[test]
+    fn command_restricts_untrusted_input() {
+        let mut tex = TexRender::from_bytes(Vec::new());
+        tex.untrusted(true);
+        let cmd = tex.command(Path::new(".")).unwrap();
+
+        let env = |name: &str| {
+            cmd.get_envs()
+                .find(|(key, _)| *key == OsStr::new(name))
+                .and_then(|(_, value)| value)
+        };
+        assert_eq!(env("openin_any"), Some(OsStr::new("p")));
+        assert_eq!(env("openout_any"), Some(OsStr::new("p")));
+        assert_eq!(env("HOME"), None);
+
+        let args = command_args(&tex);
+        assert!(args.iter().any(|arg| arg == "-norc"));
+        assert!(args.iter().any(|arg| arg == "-no-shell-escape"));
+    }
+
+    #[test]
+    fn untrusted_input_refuses_shell_escape() {
+        let doc = r"
+        \documentclass{article}
+        \begin{document}
+        \immediate\write18{cat /etc/passwd > leak.tex}
+        \end{document}
+        ";
+
+        let mut tex = TexRender::from_bytes(doc.into());
+        tex.untrusted(true);
+
+        match tex.render() {
+            Err(RenderingError::ForbiddenConstruct {
+                file,
+                line,
+                construct,
+            }) => {
+                assert_eq!(file, Path::new("input.tex"));
+                assert_eq!(line, 4);
+                assert_eq!(construct, r"\write18");
+            }
+            other => panic!("expected forbidden construct, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn untrusted_input_refuses_lua() {
+        let doc = r"
+        \documentclass{article}
+        \begin{document}
+        \csname directlua\endcsname{tex.print(io.open('/etc/passwd'):read('a'))}
+        \end{document}
+        ";
+
+        let mut tex = TexRender::from_bytes(doc.into());
+        tex.engine(TexEngine::LuaLatex)
+            .untrusted(true)
+            .backend(Fake::new());
+        match tex.render() {
+            Err(RenderingError::InvalidOption(_)) => (),
+            other => panic!("expected invalid option, got {:?}", other),
+        }
+
+        tex.engine(TexEngine::PdfLatex);
+        match tex.render() {
+            Err(RenderingError::ForbiddenConstruct { construct, .. }) => {
+                assert_eq!(construct, r"\csname directlua\endcsname");
+            }
+            other => panic!("expected forbidden construct, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn untrusted_input_cannot_read_system_files() {
+        let doc = r"
+        \documentclass{article}
+        \begin{document}
+        \input{/etc/passwd}
+        \end{document}
+        ";
+
+        let mut tex = TexRender::from_bytes(doc.into());
+        tex.untrusted(true);
+
+        match tex.render() {
+            Err(RenderingError::ForbiddenFileAccess(paths)) => {
+                assert_eq!(paths, ["/etc/passwd"]);
+            }
+            other => panic!("expected forbidden file access, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn command_selects_shell_escape() {
+        let mut tex = TexRender::from_bytes(Vec::new());
+        tex.shell_escape(ShellEscape::Restricted)
+            .shell_escape_commands(vec!["pygmentize", "bad,name", "inkscape"]);
+        let cmd = tex.command(Path::new(".")).unwrap();
+        assert!(cmd.get_args().any(|arg| arg == "-shell-restricted"));
+        let commands = cmd
+            .get_envs()
+            .find(|(key, _)| *key == OsStr::new("shell_escape_commands"))
+            .and_then(|(_, value)| value);
+        assert_eq!(commands, Some(OsStr::new("pygmentize,inkscape")));
+
+        tex.shell_escape(ShellEscape::Full);
+        let args = command_args(&tex);
+        assert!(args.iter().any(|arg| arg == "-shell-escape"));
+        assert!(!args.iter().any(|arg| arg == "-no-shell-escape"));
+
+        tex.untrusted(true);
+        let args = command_args(&tex);
+        assert!(args.iter().any(|arg| arg == "-no-shell-escape"));
+        assert!(!args.iter().any(|arg| arg == "-shell-escape"));
+    }
+
+    #[test]
+    fn full_shell_escape_runs_commands() {
+        let doc = r"
+        \documentclass{article}
+        \immediate\write18{echo hello, shell. > shell.tex}
+        \begin{document}
+        \input{shell}
+        \end{document}
+        ";
+
+        let mut tex = TexRender::from_bytes(doc.into());
+        tex.shell_escape(ShellEscape::Full);
+        let output = tex.render().unwrap();
+        assert_eq!(output.pages, Some(1));
+    }
+
+    #[test]
+    fn command_enables_reproducible_mode() {
+        let mut tex = TexRender::from_bytes(Vec::new());
+        tex.engine(TexEngine::PdfLatex)
+            .source_date(SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+        let cmd = tex.command(Path::new(".")).unwrap();
+
+        let env = |name: &str| {
+            cmd.get_envs()
+                .find(|(key, _)| *key == OsStr::new(name))
+                .and_then(|(_, value)| value)
+        };
+        assert_eq!(env("SOURCE_DATE_EPOCH"), Some(OsStr::new("1600000000")));
+        assert_eq!(env("FORCE_SOURCE_DATE"), Some(OsStr::new("1")));
+        assert!(command_args(&tex)
+            .iter()
+            .any(|arg| arg.starts_with("-usepretex=\\pdftrailerid{}")));
+
+        tex.reproducible(false);
+        assert!(!command_args(&tex)
+            .iter()
+            .any(|arg| arg.starts_with("-usepretex")));
+    }
+
+    #[test]
+    fn command_enables_synctex() {
+        let mut tex = TexRender::from_bytes(Vec::new());
+        assert!(!command_args(&tex).contains(&"-synctex=1".to_owned()));
+
+        tex.synctex(true);
+        assert!(command_args(&tex).contains(&"-synctex=1".to_owned()));
+    }
+
+    #[test]
+    fn render_reports_events() {
+        let doc = r"\documentclass{article}
+\begin{document}
+hello, world.
+\end{document}
+";
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut tex = TexRender::from_bytes(doc.into());
+        tex.engine(TexEngine::PdfLatex).on_event(move |event| {
+            let _ = sender.send(event.clone());
+        });
+        tex.render().unwrap();
+        drop(tex);
+
+        let events: Vec<RenderEvent> = receiver.iter().collect();
+        assert_eq!(events[0], RenderEvent::ToolStarted("latexmk".to_owned()));
+        assert!(events.contains(&RenderEvent::ToolStarted("pdflatex".to_owned())));
+        assert!(events.contains(&RenderEvent::PassStarted(1)));
+        assert!(events.contains(&RenderEvent::PageShipped(1)));
+    }
+
+    #[test]
+    fn render_with_synctex() {
+        let doc = r"\documentclass{article}
+\begin{document}
+hello, world.
+
+\newpage
+second page.
+\end{document}
+";
+
+        let mut tex = TexRender::from_bytes(doc.into());
+        tex.synctex(true);
+        let output = tex.render().unwrap();
+        assert_eq!(output.artifacts.len(), 1);
+        assert_eq!(output.artifacts[0].name, "input.synctex.gz");
+
+        let synctex = SyncTex::parse(&output.artifacts[0].data).unwrap();
+        assert_eq!(synctex.pages(), 2);
+
+        let positions = synctex.output_at("input.tex", 6);
+        assert_eq!(positions.len(), 1);
+        assert_eq!(positions[0].page, 2);
+
+        let source = synctex
+            .source_at(2, positions[0].x + 1.0, positions[0].y + 1.0)
+            .unwrap();
+        assert_eq!(source.file, Path::new("input.tex"));
+        assert_eq!(source.line, 6);
+    }
+
+    #[test]
+    fn reproducible_renders_are_identical() {
+        let doc = r"
+        \documentclass{article}
+        \begin{document}
+        Rendered on \today.
+        \end{document}
+        ";
+
+        for engine in &[TexEngine::PdfLatex, TexEngine::XeLatex, TexEngine::LuaLatex] {
+            let mut tex = TexRender::from_bytes(doc.into());
+            tex.engine(*engine).reproducible(true);
+
+            let first = tex.render().unwrap();
+            std::thread::sleep(Duration::from_millis(1100));
+            let second = tex.render().unwrap();
+            assert!(
+                first.files[0].data == second.files[0].data,
+                "{:?} output differs",
+                engine
+            );
+        }
+    }
+
+    #[test]
+    fn command_passes_extra_options() {
+        let build_dir = tempdir::TempDir::new("texrender-test").unwrap();
+        let mut tex = TexRender::from_bytes(Vec::new());
+        tex.env("TEXMFHOME", "/texmf")
+            .env_remove("TEXMFVAR")
+            .add_arg("-recorder")
+            .add_engine_arg("-synctex=1")
+            .latexmkrc("add_cus_dep('glo', 'gls', 0, 'makeglossaries');");
+        let cmd = tex.command(build_dir.path()).unwrap();
+
+        let env = |name: &str| {
+            cmd.get_envs()
+                .find(|(key, _)| *key == OsStr::new(name))
+                .map(|(_, value)| value)
+        };
+        assert_eq!(env("TEXMFHOME"), Some(Some(OsStr::new("/texmf"))));
+        assert_eq!(env("TEXMFVAR"), Some(None));
+
+        let args: Vec<_> = cmd.get_args().collect();
+        assert_eq!(
+            args[args.len() - 5..],
+            [
+                "-r",
+                ".texrender-latexmkrc",
+                "-latexoption=-synctex=1",
+                "-recorder",
+                "input.tex"
+            ]
+        );
+        let rc = std::fs::read_to_string(build_dir.path().join(".texrender-latexmkrc")).unwrap();
+        assert!(rc.contains("makeglossaries"));
+
+        tex.inherit_env(false);
+        let cmd = tex.command(build_dir.path()).unwrap();
+        let names: Vec<_> = cmd.get_envs().map(|(key, _)| key).collect();
+        assert!(names.contains(&OsStr::new("TEXMFHOME")));
+        assert!(!names.contains(&OsStr::new("HOME")));
+    }
+
+    #[test]
+    fn protected_options_are_refused() {
+        let refused: &[fn(&mut TexRender)] = &[
+            |tex| {
+                tex.env("openin_any", "a");
+            },
+            |tex| {
+                tex.env_remove("TEXINPUTS");
+            },
+            |tex| {
+                tex.env("openin_any.xelatex", "a");
+            },
+            |tex| {
+                tex.env("openout_any_pdflatex", "a");
+            },
+            |tex| {
+                tex.env("TEXINPUTS.xelatex", "/etc//");
+            },
+            |tex| {
+                tex.env("TEXINPUTS_lualatex", "/etc//");
+            },
+            |tex| {
+                tex.add_arg("-deps-out=/tmp/deps");
+            },
+            |tex| {
+                tex.add_arg("-MF");
+            },
+            |tex| {
+                tex.add_arg("--outdir=/tmp");
+            },
+            |tex| {
+                tex.add_arg("-o");
+            },
+            |tex| {
+                tex.add_engine_arg("-output-driver=sh");
+            },
+            |tex| {
+                tex.add_arg("-shell-escape");
+            },
+            |tex| {
+                tex.add_arg("--output-directory=/tmp");
+            },
+            |tex| {
+                tex.add_arg("-e").add_arg("$pdflatex = 'sh'");
+            },
+            |tex| {
+                tex.add_engine_arg("-cnf-line=shell_escape=t");
+            },
+            |tex| {
+                tex.add_arg("-f");
+            },
+            |tex| {
+                tex.add_engine_arg("-kpathsea-debug=-1");
+            },
+            |tex| {
+                tex.untrusted(true).add_arg("-recorder");
+            },
+        ];
+
+        for refuse in refused {
+            let mut tex = TexRender::from_bytes(Vec::new());
+            tex.backend(Fake::new());
+            refuse(&mut tex);
+            match tex.render() {
+                Err(RenderingError::InvalidOption(_)) => (),
+                other => panic!("expected invalid option, got {:?}", other),
+            }
+        }
+
+        let mut tex = TexRender::from_bytes(Vec::new());
+        tex.backend(Fake::new())
+            .env("TEXMFHOME", "/texmf")
+            .add_arg("-silent")
+            .add_arg("-recorder");
+        tex.render().unwrap();
+    }
+
+    #[test]
+    fn concurrent_render_to_path_calls_do_not_collide() {
+        let out_dir = tempdir::TempDir::new("texrender-test").unwrap();
+        let out_file = out_dir.path().join("out.pdf");
+        let mut tex = TexRender::from_bytes(Vec::new());
+        tex.backend(Fake::new().output(b"%PDF-fake".to_vec()).clone());
+
+        std::thread::scope(|scope| {
+            let renders: Vec<_> = (0..8)
+                .map(|_| scope.spawn(|| tex.render_to_path(&out_file)))
+                .collect();
+            for render in renders {
+                render.join().unwrap().unwrap();
+            }
+        });
+        assert_eq!(std::fs::read(&out_file).unwrap(), b"%PDF-fake");
+        assert_eq!(std::fs::read_dir(out_dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn render_returns_artifacts() {
+        let cache_dir = tempdir::TempDir::new("texrender-test").unwrap();
+        let mut tex = TexRender::from_bytes(Vec::new());
+        tex.backend(Fake::new())
+            .add_artifact(".log")
+            .add_artifact("input.aux")
+            .cache(RenderCache::open(cache_dir.path(), 1 << 20).unwrap());
+
+        let rendered = tex.render().unwrap();
+        assert_eq!(rendered.artifacts.len(), 1);
+        assert_eq!(rendered.artifacts[0].name, "input.log");
+        assert!(rendered.artifacts[0].data.starts_with(b"Output written on"));
+
+        let cached = tex.render().unwrap();
+        assert_eq!(cached.passes, 0);
+        assert_eq!(cached.artifacts[0].name, "input.log");
+        assert_eq!(cached.artifacts[0].data, rendered.artifacts[0].data);
+    }
+
+    #[test]
+    fn report_quotes_sources() {
+        let mut fake = Fake::new();
+        fake.fail(Some(12), Vec::new(), Vec::new())
+            .log(b"./main.tex:2: Undefined control sequence.\nl.2 hello \\foo\n".to_vec());
+        let mut tex = TexRender::from_sources(
+            vec![("doc/main.tex", b"\\begin{document}\nhello \\foo\n".to_vec())],
+            "doc/main.tex",
+        )
+        .unwrap();
+        tex.backend(fake);
+
+        let err = tex.render().unwrap_err();
+        assert_eq!(
+            tex.report(&err).to_string(),
+            "error: Undefined control sequence.
+  --> main.tex:2
+  |
+1 | \\begin{document}
+2 | hello \\foo
+  |       ^^^^
+  |
+   = exit status 12
+"
+        );
+    }
+
+    #[test]
+    fn preflight_fake_backend() {
+        let mut tex = TexRender::from_bytes(b"hello".to_vec());
+        tex.backend(Fake::new());
+
+        let report = tex.preflight().unwrap();
+        assert!(report.is_ok());
+        assert!(report.toolchain.tools.is_empty());
+    }
+
+    #[test]
+    fn preflight_reports_missing_programs() {
+        let mut direct = DirectEngine::new();
+        direct.bin_dir("/nonexistent/bin");
+        let mut tex = TexRender::from_bytes(b"hello".to_vec());
+        tex.engine(TexEngine::PdfLatex)
+            .bibliography(BibBackend::Biber)
+            .backend(direct);
+
+        let report = tex.preflight().unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(
+            report.missing_programs,
+            ["/nonexistent/bin/pdflatex", "/nonexistent/bin/biber"]
+        );
+        assert_eq!(
+            report.to_string(),
+            "missing programs: /nonexistent/bin/pdflatex, /nonexistent/bin/biber"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn preflight_checks_required_files() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let bin_dir = tempdir::TempDir::new("texrender-test").unwrap();
+        let kpsewhich = bin_dir.path().join("kpsewhich");
+        std::fs::write(
+            &kpsewhich,
+            "#!/bin/sh\n\
+             for f; do [ \"$f\" = article.cls ] && echo /texmf/article.cls; done; exit 1\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&kpsewhich, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut tex = TexRender::from_bytes(b"hello".to_vec());
+        tex.backend(Fake::new())
+            .env("PATH", bin_dir.path())
+            .require_class("article")
+            .require_package("nonexistent");
+
+        let report = tex.preflight().unwrap();
+        assert!(report.missing_programs.is_empty());
+        assert_eq!(report.missing_files, ["nonexistent.sty"]);
+        assert_eq!(report.toolchain.tool("kpsewhich").unwrap().path, kpsewhich);
+
+        tex.env("PATH", "/nonexistent/bin");
+        let report = tex.preflight().unwrap();
+        assert_eq!(report.missing_programs, ["kpsewhich"]);
+        assert!(report.missing_files.is_empty());
+    }
+
+    #[test]
+    fn fake_backend_records_renderings() {
+        let fake = Fake::new();
+        let mut tex = TexRender::from_sources(
+            vec![
+                ("main.tex", b"\\input{chapter}".to_vec()),
+                ("chapter.tex", b"hello".to_vec()),
+            ],
+            "main.tex",
+        )
+        .unwrap();
+        tex.add_asset_from_bytes("logo.pdf", b"logo").unwrap();
+        tex.engine(TexEngine::LuaLatex).backend(fake.clone());
+
+        let output = tex.render().unwrap();
+        assert_eq!(output.files[0].name, "main.pdf");
+        assert!(output.files[0].data.starts_with(b"%PDF"));
+        assert_eq!(output.pages, Some(1));
+
+        let renderings = fake.renderings();
+        assert_eq!(renderings.len(), 1);
+        assert_eq!(renderings[0].main, Path::new("main.tex"));
+        assert_eq!(renderings[0].engine, TexEngine::LuaLatex);
+        assert_eq!(
+            renderings[0].sources,
+            [
+                ("chapter.tex".into(), b"hello".to_vec()),
+                ("main.tex".into(), b"\\input{chapter}".to_vec())
+            ]
+        );
+        assert_eq!(
+            renderings[0].assets,
+            [("logo.pdf".into(), b"logo".to_vec())]
+        );
+
+        let mut failing = Fake::new();
+        failing
+            .fail(Some(12), b"out".to_vec(), Vec::new())
+            .log(b"./main.tex:3: Undefined control sequence.\nl.3 \\foo\n".to_vec());
+        tex.backend(failing);
+        match tex.render() {
+            Err(RenderingError::LatexError {
+                status,
+                stdout,
+                diagnostics,
+                ..
+            }) => {
+                assert_eq!(status, Some(12));
+                assert_eq!(stdout, b"out");
+                assert_eq!(diagnostics[0].line, Some(3));
+            }
+            other => panic!("expected latex error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn broken_tex_gives_correct_error() {
+        let doc = r"
+        \documentSOBROKENclass{article}
+        ";
+
+        let tex = TexRender::from_bytes(doc.into());
+
+        match tex.render() {
+            Err(RenderingError::LatexError { diagnostics, .. }) => {
+                assert_eq!(diagnostics[0].line, Some(2));
+            }
             other => panic!("expected latex error, got {:?}", other),
         }
     }
@@ -0,0 +1,218 @@
+//! Resource limits for rendering processes.
+//!
+//! CPU time, address space and file size are limited through rlimits, which every process
+//! started by `latexmk` inherits. The number of files has no rlimit equivalent, it is enforced by
+//! watching the build directory instead.
+
+use crate::Resource;
+use std::{fs, io, path, process, time::Duration};
+
+#[cfg(unix)]
+use libc::{SIGKILL, SIGXCPU, SIGXFSZ};
+
+// Without rlimits, processes are never killed for exceeding them; the values are placeholders.
+#[cfg(not(unix))]
+const SIGKILL: i32 = 9;
+#[cfg(not(unix))]
+const SIGXCPU: i32 = 24;
+#[cfg(not(unix))]
+const SIGXFSZ: i32 = 25;
+
+/// Limits on the resources used by a rendering.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) struct ResourceLimits {
+    /// CPU time per process.
+    pub(crate) cpu_time: Option<Duration>,
+    /// Address space per process, in bytes.
+    pub(crate) address_space: Option<u64>,
+    /// Size of every written file, in bytes.
+    pub(crate) file_size: Option<u64>,
+    /// Number of files in the build directory.
+    pub(crate) files: Option<usize>,
+}
+
+/// Tolerance when comparing used CPU time against the limit.
+const CPU_TIME_TOLERANCE: Duration = Duration::from_millis(100);
+
+/// Messages printed by shells when a process they waited for was killed for exceeding the CPU
+/// time limit (`SIGXCPU`).
+const CPU_TIME_MESSAGES: &[&str] = &["CPU time limit exceeded"];
+
+/// Messages printed when exceeding the file size limit, either by shells for a process killed
+/// through `SIGXFSZ`, or for a write failing with `EFBIG`.
+const FILE_SIZE_MESSAGES: &[&str] = &["File size limit exceeded", "File too large"];
+
+/// Messages printed by TeX, Lua and Perl when running out of memory.
+const OUT_OF_MEMORY_MESSAGES: &[&str] = &[
+    "memory exhausted",
+    "not enough memory",
+    "Out of memory",
+    "Cannot allocate memory",
+];
+
+impl ResourceLimits {
+    /// Returns whether no limits are set.
+    pub(crate) fn is_unlimited(&self) -> bool {
+        *self == ResourceLimits::default()
+    }
+
+    /// Applies the limits to a command as rlimits.
+    ///
+    /// Does nothing on platforms without rlimits.
+    pub(crate) fn apply(&self, cmd: &mut process::Command) {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+
+            let limits = *self;
+            // `set_rlimits` only calls async-signal-safe functions, as required after forking.
+            unsafe {
+                cmd.pre_exec(move || limits.set_rlimits());
+            }
+        }
+
+        #[cfg(not(unix))]
+        let _ = cmd;
+    }
+
+    /// Sets the rlimits of the current process.
+    #[cfg(unix)]
+    fn set_rlimits(&self) -> io::Result<()> {
+        macro_rules! set_rlimit {
+            ($resource:expr, $soft:expr, $hard:expr) => {{
+                let mut limit = libc::rlimit {
+                    rlim_cur: 0,
+                    rlim_max: 0,
+                };
+                if unsafe { libc::getrlimit($resource, &mut limit) } != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+
+                // Hard limits can only be lowered.
+                let hard = limit.rlim_max.min($hard as libc::rlim_t);
+                limit.rlim_cur = hard.min($soft as libc::rlim_t);
+                limit.rlim_max = hard;
+                if unsafe { libc::setrlimit($resource, &limit) } != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }};
+        }
+
+        if let Some(cpu_time) = self.cpu_time {
+            // Processes receive `SIGXCPU` at the soft limit and `SIGKILL` a second later.
+            let secs = (cpu_time.as_secs() + u64::from(cpu_time.subsec_nanos() > 0)).max(1);
+            set_rlimit!(libc::RLIMIT_CPU, secs, secs + 1);
+        }
+        if let Some(bytes) = self.address_space {
+            set_rlimit!(libc::RLIMIT_AS, bytes, bytes);
+        }
+        if let Some(bytes) = self.file_size {
+            set_rlimit!(libc::RLIMIT_FSIZE, bytes, bytes);
+        }
+
+        Ok(())
+    }
+
+    /// Checks whether `dir` holds more files than allowed.
+    pub(crate) fn too_many_files(&self, dir: &path::Path) -> bool {
+        self.files
+            .is_some_and(|max| count_files(dir).is_ok_and(|count| count > max))
+    }
+
+    /// Determines the limit exceeded by a finished process, if any.
+    ///
+    /// Rlimits apply to every process individually, so exceeding them is recognized by how a
+    /// process died: killed by `SIGXCPU`, or by `SIGKILL` at the hard CPU limit, or by `SIGXFSZ`.
+    /// For processes started by the command, e.g. the engine run by `latexmk`, the shell in
+    /// between reports the signal on stderr. `cpu_time` is the CPU time used by the process and all
+    /// processes it waited for, if known.
+    ///
+    /// Exceeding the address space limit is detected through error messages on stderr. Stdout,
+    /// which echoes the document, is only searched if the process was killed by a signal.
+    pub(crate) fn exceeded(
+        &self,
+        dir: &path::Path,
+        output: &process::Output,
+        cpu_time: Option<Duration>,
+    ) -> Option<Resource> {
+        if self.too_many_files(dir) {
+            return Some(Resource::FileCount);
+        }
+
+        // Running into any other limit kills the process or makes it fail.
+        if output.status.success() {
+            return None;
+        }
+
+        let signal = termination_signal(&output.status);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let reported = |messages: &[&str]| {
+            stderr
+                .lines()
+                .any(|line| messages.iter().any(|msg| line.contains(msg)))
+        };
+
+        if let Some(limit) = self.cpu_time {
+            // Accounted CPU time may fall slightly short of the time the kernel killed at.
+            let killed = signal == Some(SIGKILL)
+                && cpu_time.is_some_and(|used| used + CPU_TIME_TOLERANCE >= limit);
+            if signal == Some(SIGXCPU) || killed || reported(CPU_TIME_MESSAGES) {
+                return Some(Resource::CpuTime);
+            }
+        }
+
+        if self.file_size.is_some() && (signal == Some(SIGXFSZ) || reported(FILE_SIZE_MESSAGES)) {
+            return Some(Resource::FileSize);
+        }
+
+        if self.address_space.is_some() {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let in_stdout = signal.is_some()
+                && stdout
+                    .lines()
+                    .any(|line| OUT_OF_MEMORY_MESSAGES.iter().any(|msg| line.contains(msg)));
+            if in_stdout || reported(OUT_OF_MEMORY_MESSAGES) {
+                return Some(Resource::AddressSpace);
+            }
+        }
+
+        None
+    }
+}
+
+/// Returns the signal that terminated a process.
+///
+/// Shells exit with 128 plus the signal number when the command they ran was killed by a
+/// signal, which is reported as well.
+fn termination_signal(status: &process::ExitStatus) -> Option<i32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return Some(signal);
+        }
+    }
+
+    status
+        .code()
+        .filter(|code| {
+            [SIGXCPU, SIGXFSZ]
+                .iter()
+                .any(|signal| *code == 128 + signal)
+        })
+        .map(|code| code - 128)
+}
+
+/// Counts all files below `dir`.
+fn count_files(dir: &path::Path) -> io::Result<usize> {
+    let mut count = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        count += if entry.file_type()?.is_dir() {
+            count_files(&entry.path())?
+        } else {
+            1
+        };
+    }
+    Ok(count)
+}
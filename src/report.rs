@@ -0,0 +1,540 @@
+//! Human-readable error reports.
+//!
+//! An `ErrorReport` presents a `RenderingError` to humans: the first real error is shown along
+//! with an excerpt of the source it occurred in, marked with line numbers and a caret, followed by
+//! the exit status. Reports are rendered as plain text or with ANSI colors for terminals.
+
+use crate::{
+    diagnostics::{self, Diagnostic, Severity},
+    tpl::source_map::SourceMap,
+    RenderingError,
+};
+use std::{fmt, path};
+
+/// Number of source lines shown before and after the offending line.
+const EXCERPT_LINES: u32 = 2;
+
+/// ANSI escape sequence for errors.
+const ANSI_ERROR: &str = "\x1b[1;31m";
+/// ANSI escape sequence for line numbers and markers.
+const ANSI_MARKER: &str = "\x1b[1;34m";
+/// ANSI escape sequence for emphasized text.
+const ANSI_BOLD: &str = "\x1b[1m";
+/// ANSI escape sequence resetting all attributes.
+const ANSI_RESET: &str = "\x1b[0m";
+
+/// Style a report is rendered in.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ReportStyle {
+    /// Plain text.
+    Plain,
+    /// Text colored with ANSI escape sequences.
+    Ansi,
+}
+
+/// A human-readable report of a `RenderingError`.
+///
+/// Excerpts are only shown for sources added through `source`; `TexRender::report` creates a
+/// report with all sources of a rendering. Errors in documents generated through `tpl` are
+/// annotated with the element that caused them if a source map is added through `source_map`.
+/// The `Display` implementation renders plain text.
+#[derive(Debug)]
+pub struct ErrorReport<'a> {
+    /// The error to report.
+    error: &'a RenderingError,
+    /// Sources to quote, with paths relative to the directory of the main file.
+    sources: Vec<(path::PathBuf, &'a [u8])>,
+    /// Source maps of generated sources, with paths relative to the directory of the main file.
+    source_maps: Vec<(path::PathBuf, &'a SourceMap)>,
+}
+
+impl<'a> ErrorReport<'a> {
+    /// Creates a report of `error`, without any sources to quote.
+    pub fn new(error: &'a RenderingError) -> Self {
+        ErrorReport {
+            error,
+            sources: Vec::new(),
+            source_maps: Vec::new(),
+        }
+    }
+
+    /// Adds a source file to quote excerpts from.
+    ///
+    /// `path` is relative to the directory of the main file, like the paths TeX reports.
+    pub fn source<P: Into<path::PathBuf>>(&mut self, path: P, contents: &'a [u8]) -> &mut Self {
+        self.sources.push((path.into(), contents));
+        self
+    }
+
+    /// Adds the source map of a source generated through `tpl`.
+    ///
+    /// `path` is the name the source was rendered as, e.g. `input.tex` for
+    /// `TexRender::from_bytes`.
+    pub fn source_map<P: Into<path::PathBuf>>(&mut self, path: P, map: &'a SourceMap) -> &mut Self {
+        self.source_maps.push((path.into(), map));
+        self
+    }
+
+    /// Renders the report.
+    pub fn render(&self, style: ReportStyle) -> String {
+        let mut out = Output {
+            text: String::new(),
+            style,
+        };
+
+        match self.error {
+            RenderingError::LatexError {
+                status,
+                stdout,
+                stderr,
+                diagnostics,
+            } => {
+                match first_error(stdout, diagnostics) {
+                    Some(diag) => {
+                        out.headline(&diag.message);
+                        if let (Some(file), Some(line)) = (&diag.file, diag.line) {
+                            let caret = context_fragment(&diag.context);
+                            self.excerpt(&mut out, file, line, caret, true);
+                            self.element_note(&mut out, file, line);
+                        }
+                    }
+                    None => out.headline(&last_line(stderr)),
+                }
+                out.note(&exit_status(status));
+            }
+            RenderingError::ForbiddenConstruct {
+                file,
+                line,
+                construct,
+            } => {
+                out.headline(&format!("{} is not allowed in untrusted input", construct));
+                self.excerpt(&mut out, file, *line, Some(construct), false);
+                self.element_note(&mut out, file, *line);
+            }
+            RenderingError::ConversionError {
+                tool,
+                status,
+                stderr,
+            } => {
+                out.headline(&format!("conversion with {} failed", tool));
+                for line in String::from_utf8_lossy(stderr).lines() {
+                    if !line.trim().is_empty() {
+                        out.note(line.trim());
+                    }
+                }
+                out.note(&exit_status(status));
+            }
+            other => out.headline(&other.to_string()),
+        }
+
+        out.text
+    }
+
+    /// Writes the location and an excerpt of `file` around `line`.
+    ///
+    /// `marked` is the text to put a caret under. If `ends_at_error` is set, it is the input TeX
+    /// read up to the error, so only its last token is marked.
+    fn excerpt(
+        &self,
+        out: &mut Output,
+        file: &path::Path,
+        line: u32,
+        marked: Option<&str>,
+        ends_at_error: bool,
+    ) {
+        out.location(file, line);
+
+        let source = match self.find_source(file) {
+            Some(source) => String::from_utf8_lossy(source).replace('\t', " "),
+            None => return,
+        };
+        let lines: Vec<&str> = source.lines().collect();
+        if line == 0 || line as usize > lines.len() {
+            return;
+        }
+
+        let first = line.saturating_sub(EXCERPT_LINES).max(1);
+        let last = (line + EXCERPT_LINES).min(lines.len() as u32);
+        let width = last.to_string().len();
+
+        out.gutter(width, None);
+        for number in first..=last {
+            let text = lines[number as usize - 1];
+            out.gutter(width, Some(number));
+            out.text.push_str(text);
+            out.text.push('\n');
+
+            if number == line {
+                let caret = marked.and_then(|marked| caret_position(text, marked, ends_at_error));
+                if let Some((column, len)) = caret {
+                    out.gutter(width, None);
+                    out.caret(column, len);
+                }
+            }
+        }
+        out.gutter(width, None);
+    }
+
+    /// Writes the path of the `tpl` element that generated `line` of `file`, if known.
+    fn element_note(&self, out: &mut Output, file: &path::Path, line: u32) {
+        let path = self
+            .source_maps
+            .iter()
+            .filter(|(path, _)| same_file(path, file))
+            .find_map(|(_, map)| map.path_at(line));
+        if let Some(path) = path {
+            out.note(&format!("generated by {}", path));
+        }
+    }
+
+    /// Looks up the contents of a source file reported by TeX.
+    fn find_source(&self, file: &path::Path) -> Option<&'a [u8]> {
+        let file = normalize(file);
+        let by = |matches: &dyn Fn(&path::Path) -> bool| {
+            self.sources
+                .iter()
+                .find(|(path, _)| matches(&normalize(path)))
+                .map(|(_, contents)| *contents)
+        };
+
+        by(&|path| path == file)
+            .or_else(|| by(&|path| path.ends_with(&file)))
+            .or_else(|| by(&|path| file.ends_with(path)))
+    }
+}
+
+impl fmt::Display for ErrorReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.render(ReportStyle::Plain))
+    }
+}
+
+/// Report text being written.
+struct Output {
+    /// Text written so far.
+    text: String,
+    /// Style to write in.
+    style: ReportStyle,
+}
+
+impl Output {
+    /// Appends `text`, wrapped in an ANSI escape sequence if colored.
+    fn paint(&mut self, code: &str, text: &str) {
+        match self.style {
+            ReportStyle::Plain => self.text.push_str(text),
+            ReportStyle::Ansi => {
+                self.text.push_str(code);
+                self.text.push_str(text);
+                self.text.push_str(ANSI_RESET);
+            }
+        }
+    }
+
+    /// Writes the error message.
+    fn headline(&mut self, message: &str) {
+        self.paint(ANSI_ERROR, "error");
+        self.paint(ANSI_BOLD, &format!(": {}", message));
+        self.text.push('\n');
+    }
+
+    /// Writes the location of the error.
+    fn location(&mut self, file: &path::Path, line: u32) {
+        self.paint(ANSI_MARKER, "  --> ");
+        self.text
+            .push_str(&format!("{}:{}\n", normalize(file).display(), line));
+    }
+
+    /// Writes the gutter of an excerpt line, with a line number if given.
+    fn gutter(&mut self, width: usize, number: Option<u32>) {
+        let number = number.map(|n| n.to_string()).unwrap_or_default();
+        self.paint(ANSI_MARKER, &format!("{:>width$} |", number, width = width));
+        self.text
+            .push_str(if number.is_empty() { "\n" } else { " " });
+    }
+
+    /// Writes a caret line, replacing the newline of the preceding empty gutter.
+    fn caret(&mut self, column: usize, len: usize) {
+        self.text.pop();
+        self.text.push(' ');
+        self.text.push_str(&" ".repeat(column));
+        self.paint(ANSI_ERROR, &"^".repeat(len.max(1)));
+        self.text.push('\n');
+    }
+
+    /// Writes a note following the excerpt.
+    fn note(&mut self, note: &str) {
+        self.paint(ANSI_MARKER, "   = ");
+        self.text.push_str(note);
+        self.text.push('\n');
+    }
+}
+
+/// Finds the first error of a failed command, in its log diagnostics or, failing that, its output.
+pub(crate) fn first_error(stdout: &[u8], diagnostics: &[Diagnostic]) -> Option<Diagnostic> {
+    diagnostics
+        .iter()
+        .find(|diag| diag.severity == Severity::Error)
+        .cloned()
+        .or_else(|| {
+            diagnostics::parse_log(stdout)
+                .into_iter()
+                .find(|diag| diag.severity == Severity::Error)
+        })
+}
+
+/// Returns the file and line an error points at, if any.
+pub(crate) fn error_location(error: &RenderingError) -> Option<(path::PathBuf, u32)> {
+    match error {
+        RenderingError::LatexError {
+            stdout,
+            diagnostics,
+            ..
+        } => {
+            let diag = first_error(stdout, diagnostics)?;
+            Some((diag.file?, diag.line?))
+        }
+        RenderingError::ForbiddenConstruct { file, line, .. } => Some((file.clone(), *line)),
+        _ => None,
+    }
+}
+
+/// Checks whether two paths reported for a source refer to the same file.
+///
+/// Paths match if one is a suffix of the other, ignoring `.` components.
+pub(crate) fn same_file(a: &path::Path, b: &path::Path) -> bool {
+    let (a, b) = (normalize(a), normalize(b));
+    a.ends_with(&b) || b.ends_with(&a)
+}
+
+/// Summarizes the first error of a failed command in a single line.
+pub(crate) fn first_message(stdout: &[u8], stderr: &[u8], diagnostics: &[Diagnostic]) -> String {
+    match first_error(stdout, diagnostics) {
+        Some(diag) => diag.to_string(),
+        None => last_line(stderr),
+    }
+}
+
+/// Describes the exit status of a process.
+pub(crate) fn exit_status(status: &Option<i32>) -> String {
+    match status {
+        Some(code) => format!("exit status {}", code),
+        None => "terminated by signal".to_owned(),
+    }
+}
+
+/// Returns the last non-empty line of some output.
+pub(crate) fn last_line(output: &[u8]) -> String {
+    String::from_utf8_lossy(output)
+        .lines()
+        .rev()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .unwrap_or("no error message")
+        .to_owned()
+}
+
+/// Extracts the input read up to an error from TeX's context, e.g. `\foo` from `l.4 \foo`.
+fn context_fragment(context: &[String]) -> Option<&str> {
+    let line = context.first()?.strip_prefix("l.")?;
+    let digits = line.bytes().take_while(u8::is_ascii_digit).count();
+    let fragment = line[digits..].strip_prefix(' ')?;
+    // TeX elides the start of long lines.
+    Some(fragment.strip_prefix("...").unwrap_or(fragment)).filter(|f| !f.trim().is_empty())
+}
+
+/// Locates `marked` in a source line, returning the column and length of the caret in characters.
+///
+/// If `ends_at_error` is set, only the last token of `marked` is covered: a control sequence or a
+/// single character.
+fn caret_position(line: &str, marked: &str, ends_at_error: bool) -> Option<(usize, usize)> {
+    let marked = marked.replace('\t', " ");
+    let start = if ends_at_error {
+        line.rfind(&marked)
+    } else {
+        line.find(&marked)
+    }?;
+    let end = start + marked.len();
+
+    let token_start = if ends_at_error {
+        let text = line[start..end].trim_end();
+        let letters = text
+            .chars()
+            .rev()
+            .take_while(char::is_ascii_alphabetic)
+            .count();
+        let before = &text[..text.len() - letters];
+        if before.ends_with('\\') && letters > 0 {
+            start + before.len() - 1
+        } else {
+            start + text.char_indices().last()?.0
+        }
+    } else {
+        start
+    };
+    let token_end = if ends_at_error {
+        start + line[start..end].trim_end().len()
+    } else {
+        end
+    };
+
+    Some((
+        line[..token_start].chars().count(),
+        line[token_start..token_end].chars().count(),
+    ))
+}
+
+/// Removes `.` components from a path, e.g. the leading one of `./input.tex`.
+fn normalize(file: &path::Path) -> path::PathBuf {
+    file.components()
+        .filter(|component| *component != path::Component::CurDir)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ErrorReport, ReportStyle};
+    use crate::tpl::{
+        elements::{doc, raw},
+        TexElement,
+    };
+    use crate::{diagnostics::parse_log, RenderingError};
+
+    const SOURCE: &[u8] = b"\\documentclass{article}
+\\begin{document}
+hello,
+\tthis is \\foo{bar} a test.
+world.
+\\end{document}
+";
+
+    const LOG: &[u8] = b"(./input.tex
+./input.tex:4: Undefined control sequence.
+l.4  this is \\foo
+                 {bar} a test.
+";
+
+    fn latex_error() -> RenderingError {
+        RenderingError::LatexError {
+            status: Some(12),
+            stdout: b"Latexmk: Errors, so I did not complete making targets".to_vec(),
+            stderr: Vec::new(),
+            diagnostics: parse_log(LOG),
+        }
+    }
+
+    #[test]
+    fn reports_latex_errors_with_excerpt() {
+        let error = latex_error();
+        let mut report = ErrorReport::new(&error);
+        report.source("input.tex", SOURCE);
+
+        assert_eq!(
+            report.to_string(),
+            "error: Undefined control sequence.
+  --> input.tex:4
+  |
+2 | \\begin{document}
+3 | hello,
+4 |  this is \\foo{bar} a test.
+  |          ^^^^
+5 | world.
+6 | \\end{document}
+  |
+   = exit status 12
+"
+        );
+
+        let ansi = report.render(ReportStyle::Ansi);
+        assert!(ansi.contains("\x1b[1;31merror\x1b[0m"));
+        assert!(ansi.contains("\x1b[1;31m^^^^\x1b[0m"));
+    }
+
+    #[test]
+    fn reports_without_sources() {
+        let error = latex_error();
+        assert_eq!(
+            ErrorReport::new(&error).to_string(),
+            "error: Undefined control sequence.
+  --> input.tex:4
+   = exit status 12
+"
+        );
+
+        let error = RenderingError::LatexError {
+            status: None,
+            stdout: Vec::new(),
+            stderr: b"Latexmk: sorry\nkpathsea: Running mktexfmt pdflatex.fmt\n\n".to_vec(),
+            diagnostics: Vec::new(),
+        };
+        assert_eq!(
+            ErrorReport::new(&error).to_string(),
+            "error: kpathsea: Running mktexfmt pdflatex.fmt
+   = terminated by signal
+"
+        );
+    }
+
+    #[test]
+    fn reports_generating_elements() {
+        let tex = doc(vec![
+            raw("\\documentclass{article}\n\\begin{document}\nhello,\n").boxed(),
+            raw("  this is \\foo{bar} a test.\n")
+                .tagged("greeting")
+                .boxed(),
+        ]);
+        let (_, map) = tex.render_mapped().unwrap();
+
+        let error = latex_error();
+        let mut report = ErrorReport::new(&error);
+        report.source_map("input.tex", &map);
+
+        assert_eq!(
+            report.to_string(),
+            "error: Undefined control sequence.
+  --> input.tex:4
+   = generated by Group > RawTex (greeting)
+   = exit status 12
+"
+        );
+    }
+
+    #[test]
+    fn reports_forbidden_constructs() {
+        let error = RenderingError::ForbiddenConstruct {
+            file: "chapters/intro.tex".into(),
+            line: 1,
+            construct: "\\write18".to_owned(),
+        };
+        let mut report = ErrorReport::new(&error);
+        report.source("chapters/intro.tex", b"x \\immediate\\write18{ls}\n");
+
+        assert_eq!(
+            report.to_string(),
+            "error: \\write18 is not allowed in untrusted input
+  --> chapters/intro.tex:1
+  |
+1 | x \\immediate\\write18{ls}
+  |             ^^^^^^^^
+  |
+"
+        );
+    }
+
+    #[test]
+    fn error_messages_are_readable() {
+        assert_eq!(
+            latex_error().to_string(),
+            "LaTeX failure (exit status 12): ./input.tex:4: Undefined control sequence."
+        );
+
+        let error = RenderingError::ConversionError {
+            tool: "dvisvgm",
+            status: Some(1),
+            stderr: b"pre-processing DVI file\nERROR: font not found\n".to_vec(),
+        };
+        assert_eq!(
+            error.to_string(),
+            "conversion failure (dvisvgm, exit status 1): ERROR: font not found"
+        );
+    }
+}
@@ -0,0 +1,903 @@
+//! Supervised execution of external processes.
+//!
+//! Runs a command to completion while enforcing a wall-clock timeout, resource limits and
+//! honoring cancellation. On Unix, the command is started in its own process group, so that
+//! aborting a render also takes down every process `latexmk` spawned. Output is read line by line
+//! while the command runs, so progress events are reported as they happen.
+
+use crate::{
+    events::EventParser, limits::ResourceLimits, CancellationToken, RenderingError, Resource,
+};
+use std::{
+    io::{self, Read},
+    path, process,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Interval at which a running process is checked for timeouts and cancellation.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Maximum time output is read after a command exited.
+///
+/// Processes that detached from the command, e.g. through `setsid`, may hold its pipes open
+/// indefinitely.
+const OUTPUT_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Size of the chunks output is read in.
+const READ_CHUNK_SIZE: usize = 8192;
+
+/// Conditions a command runs under.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Supervision<'a> {
+    /// Maximum wall-clock time the command may take.
+    pub(crate) timeout: Option<Duration>,
+    /// Token to abort the command.
+    pub(crate) cancel: Option<&'a CancellationToken>,
+    /// Resource limits, along with the directory the command writes its files to.
+    pub(crate) limits: Option<(&'a ResourceLimits, &'a path::Path)>,
+    /// Parser receiving the output of the command line by line.
+    pub(crate) events: Option<&'a EventParser>,
+}
+
+impl Supervision<'_> {
+    /// Returns whether the command can be left alone until it exits.
+    fn is_passive(&self) -> bool {
+        self.timeout.is_none()
+            && self.cancel.is_none()
+            && self
+                .limits
+                .map_or(true, |(limits, _)| limits.files.is_none())
+    }
+
+    /// Returns the error to abort a command started at `started` with, if any.
+    fn abort_reason(&self, started: Instant) -> Option<RenderingError> {
+        if let Some(err) = self.interruption(started) {
+            return Some(err);
+        }
+
+        if self
+            .limits
+            .is_some_and(|(limits, dir)| limits.too_many_files(dir))
+        {
+            return Some(RenderingError::ResourceLimitExceeded(Resource::FileCount));
+        }
+
+        None
+    }
+
+    /// Async version of `abort_reason`, counting files on a blocking thread.
+    #[cfg(feature = "tokio")]
+    async fn abort_reason_async(&self, started: Instant) -> Option<RenderingError> {
+        if let Some(err) = self.interruption(started) {
+            return Some(err);
+        }
+
+        let (limits, dir) = self.limits.filter(|(limits, _)| limits.files.is_some())?;
+        let (limits, dir) = (*limits, dir.to_owned());
+        let too_many_files = tokio::task::spawn_blocking(move || limits.too_many_files(&dir))
+            .await
+            .unwrap_or(false);
+        too_many_files.then_some(RenderingError::ResourceLimitExceeded(Resource::FileCount))
+    }
+
+    /// Returns the error to abort a command started at `started` with for cancellation or
+    /// timeout, if any.
+    fn interruption(&self, started: Instant) -> Option<RenderingError> {
+        if self.cancel.is_some_and(CancellationToken::is_cancelled) {
+            return Some(RenderingError::Cancelled);
+        }
+
+        match self.timeout {
+            Some(timeout) if started.elapsed() >= timeout => Some(RenderingError::Timeout(timeout)),
+            _ => None,
+        }
+    }
+
+    /// Decides whether to keep reading the output of a command started at `started`, which exited
+    /// at `exited`.
+    ///
+    /// Reading stops `OUTPUT_DRAIN_TIMEOUT` after the exit, or fails once the command would have
+    /// been aborted.
+    fn keep_reading(&self, started: Instant, exited: Instant) -> Result<bool, RenderingError> {
+        match self.abort_reason(started) {
+            Some(err) => Err(err),
+            None => Ok(exited.elapsed() < OUTPUT_DRAIN_TIMEOUT),
+        }
+    }
+
+    /// Checks whether a finished command exceeded any resource limit.
+    fn check_exit(&self, output: &process::Output, exit: &Exit) -> Result<(), RenderingError> {
+        let exceeded = self
+            .limits
+            .and_then(|(limits, dir)| limits.exceeded(dir, output, exit.cpu_time));
+
+        match exceeded {
+            Some(resource) => Err(RenderingError::ResourceLimitExceeded(resource)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Exit of a process.
+#[derive(Debug)]
+struct Exit {
+    /// Exit status.
+    status: process::ExitStatus,
+    /// CPU time used by the process and all processes it waited for, if known.
+    cpu_time: Option<Duration>,
+}
+
+/// Runs a command, capturing its output.
+///
+/// If the timeout elapses or the cancellation token is triggered before the command finishes,
+/// its process group is killed and `RenderingError::Timeout` or `RenderingError::Cancelled`
+/// returned. Exceeding a resource limit results in `RenderingError::ResourceLimitExceeded`.
+///
+/// Processes left behind by the command may keep its output pipes open. Their output is read up
+/// to the timeout or cancellation, but no longer than `OUTPUT_DRAIN_TIMEOUT` after the command
+/// exited.
+pub(crate) fn run(
+    mut cmd: process::Command,
+    supervision: &Supervision<'_>,
+) -> Result<process::Output, RenderingError> {
+    prepare(&mut cmd, supervision);
+
+    let started = Instant::now();
+    let mut child = cmd.spawn().map_err(RenderingError::RunError)?;
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+    let events = supervision.events;
+    let stop = &AtomicBool::new(false);
+
+    let (result, stdout, stderr) = thread::scope(|scope| {
+        let stdout = scope.spawn(move || read_lines(stdout, events, stop));
+        let stderr = scope.spawn(move || read_lines(stderr, events, stop));
+
+        let mut result = supervise(&mut child, supervision, started);
+
+        // Other processes may still hold the pipes, they are not waited for indefinitely.
+        let exited = Instant::now();
+        while result.is_ok() && !(stdout.is_finished() && stderr.is_finished()) {
+            match supervision.keep_reading(started, exited) {
+                Ok(true) => thread::sleep(POLL_INTERVAL),
+                Ok(false) => break,
+                Err(err) => {
+                    result = kill_group(child.id())
+                        .map_err(RenderingError::RunError)
+                        .and(Err(err));
+                }
+            }
+        }
+        stop.store(true, Ordering::SeqCst);
+
+        (
+            result,
+            stdout.join().expect("stdout reader panicked"),
+            stderr.join().expect("stderr reader panicked"),
+        )
+    });
+
+    if let Some(events) = events {
+        events.command_finished();
+    }
+
+    let exit = result?;
+    let output = process::Output {
+        status: exit.status,
+        stdout: stdout.map_err(RenderingError::RunError)?,
+        stderr: stderr.map_err(RenderingError::RunError)?,
+    };
+
+    supervision.check_exit(&output, &exit)?;
+    Ok(output)
+}
+
+/// Runs a command asynchronously, capturing its output.
+///
+/// Async version of `run`. If the returned future is dropped before completion, the process group
+/// of the command is killed. The process is reaped by tokio, so the CPU time it used is not known
+/// when checking whether it exceeded the CPU time limit.
+#[cfg(feature = "tokio")]
+pub(crate) async fn run_async(
+    mut cmd: process::Command,
+    supervision: &Supervision<'_>,
+) -> Result<process::Output, RenderingError> {
+    prepare(&mut cmd, supervision);
+
+    let mut cmd = tokio::process::Command::from(cmd);
+    // On Unix, `GroupGuard` kills the whole process group instead.
+    #[cfg(not(unix))]
+    cmd.kill_on_drop(true);
+
+    let started = Instant::now();
+    let mut child = cmd.spawn().map_err(RenderingError::RunError)?;
+    let pid = child.id();
+    let mut guard = GroupGuard(pid);
+
+    // Output is collected outside the readers, which are dropped when giving up on them.
+    let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+    let (stdout_pipe, stderr_pipe) = (child.stdout.take(), child.stderr.take());
+    let (exit, read) = {
+        let readers = async {
+            let (stdout, stderr) = tokio::join!(
+                read_async(stdout_pipe, &mut stdout, supervision.events),
+                read_async(stderr_pipe, &mut stderr, supervision.events)
+            );
+            stdout.and(stderr)
+        };
+        tokio::pin!(readers);
+        let supervise = supervise_async(&mut child, &mut guard, supervision, started);
+        tokio::pin!(supervise);
+
+        let mut read = None;
+        let mut exit = loop {
+            tokio::select! {
+                result = &mut readers, if read.is_none() => read = Some(result),
+                exit = &mut supervise => break exit,
+            }
+        };
+
+        // Other processes may still hold the pipes, they are not waited for indefinitely.
+        let exited = Instant::now();
+        let mut checks = check_interval();
+        while exit.is_ok() && read.is_none() {
+            tokio::select! {
+                result = &mut readers => read = Some(result),
+                _ = checks.tick() => match supervision.abort_reason_async(started).await {
+                    Some(err) => {
+                        exit = match pid {
+                            Some(pid) => kill_group(pid)
+                                .map_err(RenderingError::RunError)
+                                .and(Err(err)),
+                            None => Err(err),
+                        };
+                    }
+                    None if exited.elapsed() >= OUTPUT_DRAIN_TIMEOUT => break,
+                    None => (),
+                },
+            }
+        }
+
+        (exit, read.unwrap_or(Ok(())))
+    };
+
+    if let Some(events) = supervision.events {
+        events.command_finished();
+    }
+
+    let exit = exit?;
+    read.map_err(RenderingError::RunError)?;
+    let output = process::Output {
+        status: exit.status,
+        stdout,
+        stderr,
+    };
+
+    supervision.check_exit(&output, &exit)?;
+    Ok(output)
+}
+
+/// Waits for `child` to exit asynchronously, killing it when aborted.
+#[cfg(feature = "tokio")]
+async fn supervise_async(
+    child: &mut tokio::process::Child,
+    guard: &mut GroupGuard,
+    supervision: &Supervision<'_>,
+    started: Instant,
+) -> Result<Exit, RenderingError> {
+    let mut checks = check_interval();
+    loop {
+        tokio::select! {
+            status = child.wait() => {
+                let status = status.map_err(RenderingError::RunError)?;
+                guard.0 = None;
+                return Ok(Exit {
+                    status,
+                    cpu_time: None,
+                });
+            }
+            _ = checks.tick() => {
+                if let Some(err) = supervision.abort_reason_async(started).await {
+                    guard.kill();
+                    child.kill().await.map_err(RenderingError::RunError)?;
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
+
+/// Returns an interval ticking every `POLL_INTERVAL`, for checking whether to abort a command.
+#[cfg(feature = "tokio")]
+fn check_interval() -> tokio::time::Interval {
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    interval
+}
+
+/// Reads a pipe to its end asynchronously into `buf`, passing complete lines to `events`.
+#[cfg(feature = "tokio")]
+async fn read_async<R>(
+    pipe: Option<R>,
+    buf: &mut Vec<u8>,
+    events: Option<&EventParser>,
+) -> io::Result<()>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    use tokio::io::AsyncReadExt;
+
+    let mut line_start = 0;
+    if let Some(mut pipe) = pipe {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        loop {
+            let len = pipe.read(&mut chunk).await?;
+            buf.extend_from_slice(&chunk[..len]);
+            emit_lines(buf, &mut line_start, events, len == 0);
+            if len == 0 {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Kills a process group when dropped.
+///
+/// Holds the id of the process group leader, set to `None` once the process has been reaped.
+/// Reaping is left to tokio, which also takes care of processes whose `Child` was dropped.
+#[cfg(feature = "tokio")]
+struct GroupGuard(Option<u32>);
+
+#[cfg(feature = "tokio")]
+impl GroupGuard {
+    /// Kills the process group.
+    fn kill(&mut self) {
+        if let Some(pid) = self.0.take() {
+            // Errors are ignored, the group may already be gone.
+            let _ = kill_group(pid);
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Drop for GroupGuard {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+/// Sets up standard IO, process group and resource limits of a command prior to spawning.
+fn prepare(cmd: &mut process::Command, supervision: &Supervision<'_>) {
+    cmd.stdin(process::Stdio::null())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped());
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        cmd.process_group(0);
+    }
+
+    if let Some((limits, _)) = supervision.limits {
+        limits.apply(cmd);
+    }
+
+    if let Some(events) = supervision.events {
+        events.command_started(cmd.get_program());
+    }
+}
+
+/// Waits for `child` to exit, killing it when aborted.
+fn supervise(
+    child: &mut process::Child,
+    supervision: &Supervision<'_>,
+    started: Instant,
+) -> Result<Exit, RenderingError> {
+    if supervision.is_passive() {
+        return wait(child, true)
+            .map(|exit| exit.expect("blocking wait returned early"))
+            .map_err(RenderingError::RunError);
+    }
+
+    loop {
+        if let Some(exit) = wait(child, false).map_err(RenderingError::RunError)? {
+            return Ok(exit);
+        }
+
+        match supervision.abort_reason(started) {
+            Some(err) => {
+                kill(child).map_err(RenderingError::RunError)?;
+                return Err(err);
+            }
+            None => thread::sleep(POLL_INTERVAL),
+        }
+    }
+}
+
+/// Waits for `child` to exit, returning `None` if it is still running and `block` is not set.
+fn wait(child: &mut process::Child, block: bool) -> io::Result<Option<Exit>> {
+    #[cfg(unix)]
+    return wait_pid(child.id(), block);
+
+    #[cfg(not(unix))]
+    {
+        let status = if block {
+            Some(child.wait()?)
+        } else {
+            child.try_wait()?
+        };
+        Ok(status.map(|status| Exit {
+            status,
+            cpu_time: None,
+        }))
+    }
+}
+
+/// Waits for the child process `pid` to exit, collecting its resource usage.
+///
+/// Returns `None` if the process is still running and `block` is not set.
+#[cfg(unix)]
+fn wait_pid(pid: u32, block: bool) -> io::Result<Option<Exit>> {
+    use std::os::unix::process::ExitStatusExt;
+
+    let options = if block { 0 } else { libc::WNOHANG };
+    let mut status = 0;
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+
+    loop {
+        match unsafe { libc::wait4(pid as libc::pid_t, &mut status, options, &mut usage) } {
+            0 => return Ok(None),
+            -1 => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+            _ => {
+                let time =
+                    |tv: libc::timeval| Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000);
+                return Ok(Some(Exit {
+                    status: process::ExitStatus::from_raw(status),
+                    cpu_time: Some(time(usage.ru_utime) + time(usage.ru_stime)),
+                }));
+            }
+        }
+    }
+}
+
+/// Kills the process group of `child` and reaps it.
+///
+/// On platforms without process groups, only `child` itself is killed.
+fn kill(child: &mut process::Child) -> io::Result<()> {
+    #[cfg(unix)]
+    kill_group(child.id())?;
+
+    #[cfg(not(unix))]
+    child.kill()?;
+
+    wait(child, true).map(|_| ())
+}
+
+/// Kills all processes in the process group led by `pid`, see `run`.
+///
+/// Does nothing on platforms without process groups.
+fn kill_group(pid: u32) -> io::Result<()> {
+    #[cfg(unix)]
+    if unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) } != 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ESRCH) {
+            return Err(err);
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = pid;
+
+    Ok(())
+}
+
+/// Reads a pipe to its end, passing complete lines to `events`.
+///
+/// Once `stop` is set, reading ends as soon as no more output is pending.
+fn read_lines<P: Pipe>(
+    pipe: Option<P>,
+    events: Option<&EventParser>,
+    stop: &AtomicBool,
+) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    let mut line_start = 0;
+    if let Some(mut pipe) = pipe {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        loop {
+            let len = if pipe.wait_readable(POLL_INTERVAL)? {
+                match pipe.read(&mut chunk) {
+                    Ok(len) => len,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(err),
+                }
+            } else if stop.load(Ordering::SeqCst) {
+                0
+            } else {
+                continue;
+            };
+            buf.extend_from_slice(&chunk[..len]);
+            emit_lines(&buf, &mut line_start, events, len == 0);
+            if len == 0 {
+                break;
+            }
+        }
+    }
+    Ok(buf)
+}
+
+/// Pipe connected to the output of a process.
+trait Pipe: Read {
+    /// Waits up to `timeout` for the pipe to become readable, returning whether it did.
+    ///
+    /// Always readable on platforms without `poll`, where reads block until output arrives.
+    fn wait_readable(&self, timeout: Duration) -> io::Result<bool>;
+}
+
+#[cfg(unix)]
+impl<R: Read + std::os::unix::io::AsRawFd> Pipe for R {
+    fn wait_readable(&self, timeout: Duration) -> io::Result<bool> {
+        let mut fd = libc::pollfd {
+            fd: self.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&mut fd, 1, timeout.as_millis() as libc::c_int) } {
+            -1 => {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    Ok(false)
+                } else {
+                    Err(err)
+                }
+            }
+            ready => Ok(ready > 0),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+impl Pipe for process::ChildStdout {
+    fn wait_readable(&self, _: Duration) -> io::Result<bool> {
+        Ok(true)
+    }
+}
+
+#[cfg(not(unix))]
+impl Pipe for process::ChildStderr {
+    fn wait_readable(&self, _: Duration) -> io::Result<bool> {
+        Ok(true)
+    }
+}
+
+/// Passes the complete lines of `buf` starting at `line_start` to `events`.
+///
+/// Advances `line_start` past the emitted lines. At the end of the output, an unterminated last
+/// line is emitted as well.
+fn emit_lines(buf: &[u8], line_start: &mut usize, events: Option<&EventParser>, eof: bool) {
+    let events = match events {
+        Some(events) => events,
+        None => return,
+    };
+
+    while let Some(len) = buf[*line_start..].iter().position(|&b| b == b'\n') {
+        events.line(&buf[*line_start..*line_start + len]);
+        *line_start += len + 1;
+    }
+
+    if eof && *line_start < buf.len() {
+        events.line(&buf[*line_start..]);
+        *line_start = buf.len();
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::{run, Supervision};
+    use crate::{
+        events::{EventHandler, EventParser, RenderEvent},
+        limits::ResourceLimits,
+        CancellationToken, RenderingError, Resource,
+    };
+    use std::{
+        process,
+        sync::{Arc, Mutex},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    fn sleep_in_subshell() -> process::Command {
+        // The inner `sleep` is a grandchild, it must be killed along with the shell.
+        let mut cmd = process::Command::new("sh");
+        cmd.args(["-c", "sleep 30; echo done"]);
+        cmd
+    }
+
+    fn shell(script: &str, dir: &std::path::Path) -> process::Command {
+        let mut cmd = process::Command::new("sh");
+        cmd.args(["-c", script]).current_dir(dir);
+        cmd
+    }
+
+    fn timeout(timeout: Duration) -> Supervision<'static> {
+        Supervision {
+            timeout: Some(timeout),
+            ..Supervision::default()
+        }
+    }
+
+    #[test]
+    fn captures_output() {
+        let mut cmd = process::Command::new("sh");
+        cmd.args(["-c", "echo out; echo err >&2; exit 3"]);
+
+        let output = run(cmd, &timeout(Duration::from_secs(30))).unwrap();
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(output.stdout, b"out\n");
+        assert_eq!(output.stderr, b"err\n");
+    }
+
+    #[test]
+    fn reports_events_while_running() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let parser = {
+            let events = events.clone();
+            EventParser::new(EventHandler(Arc::new(move |event: &RenderEvent| {
+                events.lock().unwrap().push((event.clone(), Instant::now()))
+            })))
+        };
+        let supervision = Supervision {
+            events: Some(&parser),
+            ..Supervision::default()
+        };
+
+        let mut cmd = process::Command::new("sh");
+        cmd.args([
+            "-c",
+            "echo 'This is pdfTeX, Version 3.14'; sleep 1; printf '[1] [2' >&2",
+        ]);
+
+        let start = Instant::now();
+        let output = run(cmd, &supervision).unwrap();
+        let finished = Instant::now();
+        assert_eq!(output.stderr, b"[1] [2");
+
+        let events = events.lock().unwrap();
+        let kinds: Vec<_> = events.iter().map(|(event, _)| event.clone()).collect();
+        assert_eq!(
+            kinds,
+            [
+                RenderEvent::ToolStarted("sh".to_owned()),
+                RenderEvent::PassStarted(1),
+                RenderEvent::PageShipped(1),
+                RenderEvent::PageShipped(2),
+            ]
+        );
+        assert!(events[1].1 - start < finished - events[1].1);
+    }
+
+    #[test]
+    fn timeout_kills_process_group() {
+        let start = Instant::now();
+        match run(sleep_in_subshell(), &timeout(Duration::from_millis(100))) {
+            Err(RenderingError::Timeout(timeout)) => {
+                assert_eq!(timeout, Duration::from_millis(100))
+            }
+            other => panic!("expected timeout, got {:?}", other),
+        }
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn cancellation_kills_process_group() {
+        let token = CancellationToken::new();
+        let canceller = {
+            let token = token.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(100));
+                token.cancel();
+            })
+        };
+
+        let start = Instant::now();
+        let supervision = Supervision {
+            cancel: Some(&token),
+            ..Supervision::default()
+        };
+        match run(sleep_in_subshell(), &supervision) {
+            Err(RenderingError::Cancelled) => (),
+            other => panic!("expected cancellation, got {:?}", other),
+        }
+        assert!(start.elapsed() < Duration::from_secs(10));
+        canceller.join().unwrap();
+    }
+
+    #[test]
+    fn detached_processes_do_not_outlast_timeout() {
+        // The detached `sleep` leaves the process group, but still holds the pipes.
+        let mut cmd = process::Command::new("sh");
+        cmd.args(["-c", "setsid sleep 5 & echo started"]);
+
+        let start = Instant::now();
+        match run(cmd, &timeout(Duration::from_millis(500))) {
+            Err(RenderingError::Timeout(_)) => (),
+            other => panic!("expected timeout, got {:?}", other),
+        }
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
+
+    #[test]
+    fn cpu_time_limit_kills_process() {
+        let dir = tempdir::TempDir::new("texrender-test").unwrap();
+        let limits = ResourceLimits {
+            cpu_time: Some(Duration::from_secs(1)),
+            ..ResourceLimits::default()
+        };
+        let supervision = Supervision {
+            timeout: Some(Duration::from_secs(30)),
+            limits: Some((&limits, dir.path())),
+            ..Supervision::default()
+        };
+
+        match run(shell("while :; do :; done", dir.path()), &supervision) {
+            Err(RenderingError::ResourceLimitExceeded(Resource::CpuTime)) => (),
+            other => panic!("expected CPU time limit, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn file_limits_are_enforced() {
+        let dir = tempdir::TempDir::new("texrender-test").unwrap();
+        let limits = ResourceLimits {
+            file_size: Some(1000),
+            files: Some(3),
+            ..ResourceLimits::default()
+        };
+        let supervision = Supervision {
+            timeout: Some(Duration::from_secs(30)),
+            limits: Some((&limits, dir.path())),
+            ..Supervision::default()
+        };
+
+        let script = "head -c 10000 /dev/zero > big";
+        match run(shell(script, dir.path()), &supervision) {
+            Err(RenderingError::ResourceLimitExceeded(Resource::FileSize)) => (),
+            other => panic!("expected file size limit, got {:?}", other),
+        }
+
+        let start = Instant::now();
+        let script = "touch a b c d e; sleep 30";
+        match run(shell(script, dir.path()), &supervision) {
+            Err(RenderingError::ResourceLimitExceeded(Resource::FileCount)) => (),
+            other => panic!("expected file count limit, got {:?}", other),
+        }
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn limits_exceeded_by_grandchildren_are_recognized() {
+        let dir = tempdir::TempDir::new("texrender-test").unwrap();
+        let limits = ResourceLimits {
+            cpu_time: Some(Duration::from_secs(1)),
+            ..ResourceLimits::default()
+        };
+        let supervision = Supervision {
+            timeout: Some(Duration::from_secs(30)),
+            limits: Some((&limits, dir.path())),
+            ..Supervision::default()
+        };
+
+        // Like `latexmk`, the outer shell outlives the killed process and fails on its own.
+        let script = "sh -c 'while :; do :; done'; exit 12";
+        match run(shell(script, dir.path()), &supervision) {
+            Err(RenderingError::ResourceLimitExceeded(Resource::CpuTime)) => (),
+            other => panic!("expected CPU time limit, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn failures_within_limits_are_not_misreported() {
+        let dir = tempdir::TempDir::new("texrender-test").unwrap();
+        let limits = ResourceLimits {
+            cpu_time: Some(Duration::from_secs(1)),
+            file_size: Some(1000),
+            ..ResourceLimits::default()
+        };
+        let supervision = Supervision {
+            timeout: Some(Duration::from_secs(30)),
+            limits: Some((&limits, dir.path())),
+            ..Supervision::default()
+        };
+
+        // Two passes staying below the CPU limit each, but exceeding it together.
+        let script = "head -c 1000 /dev/zero > exact; \
+                      timeout 0.7 sh -c 'while :; do :; done'; \
+                      timeout 0.7 sh -c 'while :; do :; done'; \
+                      exit 1";
+        let output = run(shell(script, dir.path()), &supervision).unwrap();
+        assert_eq!(output.status.code(), Some(1));
+    }
+
+    #[test]
+    fn out_of_memory_is_recognized_on_stderr_only() {
+        let dir = tempdir::TempDir::new("texrender-test").unwrap();
+        let limits = ResourceLimits {
+            address_space: Some(1 << 32),
+            ..ResourceLimits::default()
+        };
+        let supervision = Supervision {
+            timeout: Some(Duration::from_secs(30)),
+            limits: Some((&limits, dir.path())),
+            ..Supervision::default()
+        };
+
+        // Documents are echoed on stdout, e.g. through `\typeout`.
+        let output = run(
+            shell("echo 'Out of memory'; exit 1", dir.path()),
+            &supervision,
+        )
+        .unwrap();
+        assert_eq!(output.status.code(), Some(1));
+
+        let script = "echo 'fatal: memory exhausted' >&2; exit 1";
+        match run(shell(script, dir.path()), &supervision) {
+            Err(RenderingError::ResourceLimitExceeded(Resource::AddressSpace)) => (),
+            other => panic!("expected address space limit, got {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn async_detached_processes_do_not_outlast_timeout() {
+        let mut cmd = process::Command::new("sh");
+        cmd.args(["-c", "setsid sleep 5 & echo started"]);
+
+        let start = Instant::now();
+        match super::run_async(cmd, &timeout(Duration::from_millis(500))).await {
+            Err(RenderingError::Timeout(_)) => (),
+            other => panic!("expected timeout, got {:?}", other),
+        }
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn async_file_count_limit_is_enforced() {
+        let dir = tempdir::TempDir::new("texrender-test").unwrap();
+        let limits = ResourceLimits {
+            files: Some(3),
+            ..ResourceLimits::default()
+        };
+        let supervision = Supervision {
+            timeout: Some(Duration::from_secs(30)),
+            limits: Some((&limits, dir.path())),
+            ..Supervision::default()
+        };
+
+        let start = Instant::now();
+        let cmd = shell("touch a b c d e; sleep 30", dir.path());
+        match super::run_async(cmd, &supervision).await {
+            Err(RenderingError::ResourceLimitExceeded(Resource::FileCount)) => (),
+            other => panic!("expected file count limit, got {:?}", other),
+        }
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn async_timeout_kills_process_group() {
+        let start = Instant::now();
+        match super::run_async(sleep_in_subshell(), &timeout(Duration::from_millis(100))).await {
+            Err(RenderingError::Timeout(_)) => (),
+            other => panic!("expected timeout, got {:?}", other),
+        }
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,220 @@
+//! kpathsea search paths.
+//!
+//! TeX and its companion tools locate files through kpathsea, which reads a separate search path
+//! for each kind of file from the environment. `TexRender` assembles these variables from the
+//! folders added through `TexRender::add_search_path` and friends.
+
+use crate::cache::KeyBuilder;
+use std::{collections::HashMap, ffi::OsString, io, path};
+
+/// Environment variable holding a kpathsea search path.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub enum SearchVariable {
+    /// `TEXINPUTS`: classes, packages and files included from TeX.
+    TexInputs,
+    /// `BIBINPUTS`: BibTeX databases.
+    BibInputs,
+    /// `BSTINPUTS`: BibTeX styles.
+    BstInputs,
+    /// `OSFONTDIR`: system fonts, used by XeTeX, LuaTeX and the OpenType and TrueType font paths.
+    OsFontDir,
+    /// `LUAINPUTS`: Lua modules loaded by LuaTeX.
+    LuaInputs,
+    /// `INDEXSTYLE`: `makeindex` styles.
+    IndexStyle,
+}
+
+impl SearchVariable {
+    /// All search variables.
+    pub const ALL: [SearchVariable; 6] = [
+        SearchVariable::TexInputs,
+        SearchVariable::BibInputs,
+        SearchVariable::BstInputs,
+        SearchVariable::OsFontDir,
+        SearchVariable::LuaInputs,
+        SearchVariable::IndexStyle,
+    ];
+
+    /// Returns the name of the environment variable.
+    pub fn name(self) -> &'static str {
+        match self {
+            SearchVariable::TexInputs => "TEXINPUTS",
+            SearchVariable::BibInputs => "BIBINPUTS",
+            SearchVariable::BstInputs => "BSTINPUTS",
+            SearchVariable::OsFontDir => "OSFONTDIR",
+            SearchVariable::LuaInputs => "LUAINPUTS",
+            SearchVariable::IndexStyle => "INDEXSTYLE",
+        }
+    }
+}
+
+/// Position of the system default path within a search path.
+///
+/// kpathsea expands an empty entry in a search path to the default path configured in
+/// `texmf.cnf`. The default position is `Before`, so files of the TeX installation take
+/// precedence over added folders.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DefaultPath {
+    /// Search the default path before all added folders.
+    Before,
+    /// Search the default path after all added folders.
+    After,
+    /// Do not search the default path at all.
+    Never,
+}
+
+impl Default for DefaultPath {
+    #[inline]
+    fn default() -> Self {
+        DefaultPath::Before
+    }
+}
+
+/// A folder on a search path.
+#[derive(Clone, Debug)]
+struct Entry {
+    /// Path of the folder.
+    path: path::PathBuf,
+    /// Whether subfolders are searched as well.
+    recursive: bool,
+}
+
+/// Search paths for all variables.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SearchPaths {
+    /// Added folders, per variable and in order.
+    entries: HashMap<SearchVariable, Vec<Entry>>,
+    /// Positions of the default path, if not `DefaultPath::Before`.
+    defaults: HashMap<SearchVariable, DefaultPath>,
+}
+
+impl SearchPaths {
+    /// Adds a folder to a search path.
+    pub(crate) fn add(&mut self, var: SearchVariable, path: path::PathBuf, recursive: bool) {
+        self.entries
+            .entry(var)
+            .or_default()
+            .push(Entry { path, recursive });
+    }
+
+    /// Sets the position of the default path within a search path.
+    pub(crate) fn set_default(&mut self, var: SearchVariable, default: DefaultPath) {
+        self.defaults.insert(var, default);
+    }
+
+    /// Returns the position of the default path within a search path.
+    fn default_path(&self, var: SearchVariable) -> DefaultPath {
+        self.defaults.get(&var).copied().unwrap_or_default()
+    }
+
+    /// Returns the value of a search variable.
+    ///
+    /// Returns `None` if the variable should be left unset, which is the case if neither folders
+    /// were added nor the position of the default path changed.
+    pub(crate) fn value(&self, var: SearchVariable) -> Option<OsString> {
+        let entries = self.entries.get(&var).map(Vec::as_slice).unwrap_or(&[]);
+        let default = self.default_path(var);
+        if entries.is_empty() && default == DefaultPath::Before {
+            return None;
+        }
+
+        let mut value = OsString::new();
+        for (idx, entry) in entries.iter().enumerate() {
+            if idx > 0 || default == DefaultPath::Before {
+                value.push(":");
+            }
+            value.push(entry.path.as_os_str());
+            if entry.recursive {
+                value.push("//");
+            }
+        }
+        if default == DefaultPath::After {
+            value.push(":");
+        }
+
+        Some(value)
+    }
+
+    /// Returns all variables to set, along with their values.
+    pub(crate) fn env(&self) -> Vec<(&'static str, OsString)> {
+        SearchVariable::ALL
+            .iter()
+            .filter_map(|&var| self.value(var).map(|value| (var.name(), value)))
+            .collect()
+    }
+
+    /// Adds the search paths to a cache key.
+    ///
+    /// The `assets` folder is hashed by its contents, regardless of location. Other folders may
+    /// hold entire TeX trees, their files are hashed by path, size and modification time only.
+    /// Each folder is scanned only once, even if it appears on several search paths.
+    pub(crate) fn hash(&self, key: &mut KeyBuilder, assets: Option<&path::Path>) -> io::Result<()> {
+        let mut seen: Vec<&path::Path> = Vec::new();
+
+        for var in SearchVariable::ALL.iter() {
+            let entries = self.entries.get(var).map(Vec::as_slice).unwrap_or(&[]);
+            key.bytes(var.name().as_bytes())
+                .bytes(format!("{:?}", self.default_path(*var)).as_bytes())
+                .bytes(&(entries.len() as u64).to_le_bytes());
+
+            for entry in entries {
+                key.bytes(&[entry.recursive as u8]);
+                match seen.iter().position(|path| *path == entry.path) {
+                    Some(idx) => {
+                        key.bytes(&(idx as u64).to_le_bytes());
+                    }
+                    None if Some(entry.path.as_path()) == assets => {
+                        key.dir(&entry.path)?;
+                        seen.push(&entry.path);
+                    }
+                    None => {
+                        key.dir_metadata(&entry.path)?;
+                        seen.push(&entry.path);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DefaultPath, SearchPaths, SearchVariable};
+    use std::ffi::OsStr;
+
+    #[test]
+    fn builds_variable_values() {
+        let mut paths = SearchPaths::default();
+        assert_eq!(paths.value(SearchVariable::TexInputs), None);
+
+        paths.add(SearchVariable::TexInputs, "/foo".into(), false);
+        paths.add(SearchVariable::TexInputs, "/bar".into(), true);
+        assert_eq!(
+            paths.value(SearchVariable::TexInputs).as_deref(),
+            Some(OsStr::new(":/foo:/bar//"))
+        );
+
+        paths.set_default(SearchVariable::TexInputs, DefaultPath::After);
+        assert_eq!(
+            paths.value(SearchVariable::TexInputs).as_deref(),
+            Some(OsStr::new("/foo:/bar//:"))
+        );
+
+        paths.set_default(SearchVariable::TexInputs, DefaultPath::Never);
+        assert_eq!(
+            paths.value(SearchVariable::TexInputs).as_deref(),
+            Some(OsStr::new("/foo:/bar//"))
+        );
+
+        paths.set_default(SearchVariable::LuaInputs, DefaultPath::Never);
+        assert_eq!(
+            paths.value(SearchVariable::LuaInputs).as_deref(),
+            Some(OsStr::new(""))
+        );
+
+        let names: Vec<_> = paths.env().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["TEXINPUTS", "LUAINPUTS"]);
+    }
+}
@@ -0,0 +1,463 @@
+//! SyncTeX parsing.
+//!
+//! SyncTeX files map positions in the output back to the source and vice versa. They are written
+//! when rendering with `TexRender::synctex` enabled and returned among the artifacts as
+//! `<jobname>.synctex.gz`.
+//!
+//! Positions in the output are given in PDF points (1/72 inch), measured from the top left corner
+//! of the page, with the y axis pointing downwards.
+
+use flate2::read::GzDecoder;
+use std::{collections::HashMap, io, io::Read, path};
+
+/// Scaled points per PDF point.
+const SP_PER_BP: f64 = 65781.76;
+
+/// A position in a source file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SourcePosition {
+    /// Path of the source file.
+    ///
+    /// Files inside the project are relative to the directory of the main file, other files like
+    /// classes or packages keep their absolute path.
+    pub file: path::PathBuf,
+    /// Line number, starting at 1.
+    pub line: u32,
+}
+
+/// An area in the output.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutputPosition {
+    /// Page number, starting at 1.
+    pub page: u32,
+    /// Distance of the left edge from the left edge of the page.
+    pub x: f64,
+    /// Distance of the top edge from the top edge of the page.
+    pub y: f64,
+    /// Width of the area.
+    pub width: f64,
+    /// Height of the area.
+    pub height: f64,
+}
+
+/// Kind of a SyncTeX record.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Kind {
+    /// A box, with an extent.
+    Box,
+    /// A point-like node, e.g. glue, a kern or math.
+    Point,
+}
+
+/// A single node recorded by SyncTeX, with coordinates in PDF points.
+#[derive(Clone, Debug)]
+struct Record {
+    /// Tag of the input file.
+    tag: u32,
+    /// Line in the input file.
+    line: u32,
+    /// Kind of the node.
+    kind: Kind,
+    /// Horizontal position of the reference point.
+    x: f64,
+    /// Vertical position of the baseline.
+    y: f64,
+    /// Width, for boxes.
+    width: f64,
+    /// Height above the baseline, for boxes.
+    height: f64,
+    /// Depth below the baseline, for boxes.
+    depth: f64,
+}
+
+impl Record {
+    /// Returns whether the record is a box containing the point.
+    fn contains(&self, x: f64, y: f64) -> bool {
+        self.kind == Kind::Box
+            && self.width > 0.0
+            && x >= self.x
+            && x <= self.x + self.width
+            && y >= self.y - self.height
+            && y <= self.y + self.depth
+    }
+
+    /// Returns the area covered by the record.
+    fn area(&self) -> f64 {
+        self.width * (self.height + self.depth)
+    }
+
+    /// Returns the distance of the reference point to a point.
+    fn distance(&self, x: f64, y: f64) -> f64 {
+        (self.x - x).hypot(self.y - y)
+    }
+}
+
+/// A parsed SyncTeX file.
+#[derive(Clone, Debug, Default)]
+pub struct SyncTex {
+    /// Input files, by tag.
+    inputs: HashMap<u32, path::PathBuf>,
+    /// Records of every page, starting with page 1.
+    pages: Vec<Vec<Record>>,
+}
+
+/// Unit conversion settings from the preamble.
+#[derive(Copy, Clone, Debug)]
+struct Scale {
+    /// Size of a coordinate unit in scaled points.
+    unit: f64,
+    /// Magnification, in thousandths.
+    magnification: f64,
+    /// Horizontal offset in scaled points.
+    x_offset: f64,
+    /// Vertical offset in scaled points.
+    y_offset: f64,
+}
+
+impl Scale {
+    /// Converts a horizontal coordinate to PDF points.
+    fn x(&self, value: i64) -> f64 {
+        self.length(value) + self.x_offset / SP_PER_BP
+    }
+
+    /// Converts a vertical coordinate to PDF points.
+    fn y(&self, value: i64) -> f64 {
+        self.length(value) + self.y_offset / SP_PER_BP
+    }
+
+    /// Converts a length to PDF points.
+    fn length(&self, value: i64) -> f64 {
+        value as f64 * self.unit * self.magnification / 1000.0 / SP_PER_BP
+    }
+}
+
+impl SyncTex {
+    /// Parses a SyncTeX file, either gzip-compressed or not.
+    ///
+    /// Unknown records are skipped. Fails only on IO and decompression errors or if the data is
+    /// not a SyncTeX file at all.
+    pub fn parse(data: &[u8]) -> io::Result<SyncTex> {
+        let mut text = String::new();
+        if data.starts_with(&[0x1f, 0x8b]) {
+            let mut raw = Vec::new();
+            GzDecoder::new(data).read_to_end(&mut raw)?;
+            text = String::from_utf8_lossy(&raw).into_owned();
+        } else {
+            text.push_str(&String::from_utf8_lossy(data));
+        }
+
+        if !text.starts_with("SyncTeX Version:") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a SyncTeX file",
+            ));
+        }
+
+        let mut synctex = SyncTex::default();
+        let mut scale = Scale {
+            unit: 1.0,
+            magnification: 1000.0,
+            x_offset: 0.0,
+            y_offset: 0.0,
+        };
+        let mut page: Option<usize> = None;
+
+        for line in text.lines() {
+            if let Some(input) = line.strip_prefix("Input:") {
+                if let Some((tag, file)) = input.split_once(':') {
+                    if let Ok(tag) = tag.parse() {
+                        synctex.inputs.insert(tag, normalize_input(file));
+                    }
+                }
+                continue;
+            }
+
+            if let Some((key, value)) = line.split_once(':') {
+                let value = value.trim().parse::<f64>();
+                match (key, value) {
+                    ("Unit", Ok(value)) => scale.unit = value,
+                    ("Magnification", Ok(value)) => scale.magnification = value,
+                    ("X Offset", Ok(value)) => scale.x_offset = value,
+                    ("Y Offset", Ok(value)) => scale.y_offset = value,
+                    _ => (),
+                }
+            }
+
+            let mut chars = line.chars();
+            let marker = match chars.next() {
+                Some(marker) => marker,
+                None => continue,
+            };
+            let rest = chars.as_str();
+
+            match marker {
+                '{' => {
+                    if let Ok(number) = rest.parse::<usize>() {
+                        if number > 0 {
+                            if synctex.pages.len() < number {
+                                synctex.pages.resize(number, Vec::new());
+                            }
+                            page = Some(number - 1);
+                        }
+                    }
+                }
+                '}' => page = None,
+                '[' | '(' | 'v' | 'h' | 'r' | 'x' | 'k' | 'g' | '$' => {
+                    let kind = match marker {
+                        '[' | '(' | 'v' | 'h' | 'r' => Kind::Box,
+                        _ => Kind::Point,
+                    };
+                    if let (Some(page), Some(record)) = (page, parse_record(rest, kind, &scale)) {
+                        synctex.pages[page].push(record);
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        Ok(synctex)
+    }
+
+    /// Returns the number of pages.
+    pub fn pages(&self) -> u32 {
+        self.pages.len() as u32
+    }
+
+    /// Returns the paths of all input files.
+    pub fn inputs(&self) -> Vec<&path::Path> {
+        let mut inputs: Vec<_> = self.inputs.values().map(path::PathBuf::as_path).collect();
+        inputs.sort();
+        inputs
+    }
+
+    /// Finds the source position that produced the content at `(x, y)` on `page`.
+    ///
+    /// Picks the innermost box containing the point, then the last node inside it that lies
+    /// left of the point on the same line. Falls back to the nearest node if no box contains the
+    /// point.
+    pub fn source_at(&self, page: u32, x: f64, y: f64) -> Option<SourcePosition> {
+        let records = self.pages.get(page.checked_sub(1)? as usize)?;
+
+        let innermost = records
+            .iter()
+            .filter(|record| record.contains(x, y))
+            .min_by(|a, b| a.area().total_cmp(&b.area()));
+
+        let record = match innermost {
+            Some(outer) => records
+                .iter()
+                .filter(|record| record.kind == Kind::Point)
+                .filter(|record| {
+                    record.x >= outer.x
+                        && record.x <= x
+                        && record.y >= outer.y - outer.height
+                        && record.y <= outer.y + outer.depth
+                })
+                .max_by(|a, b| a.x.total_cmp(&b.x))
+                .unwrap_or(outer),
+            None => records
+                .iter()
+                .min_by(|a, b| a.distance(x, y).total_cmp(&b.distance(x, y)))?,
+        };
+
+        Some(SourcePosition {
+            file: self.inputs.get(&record.tag)?.clone(),
+            line: record.line,
+        })
+    }
+
+    /// Finds the areas in the output produced by `line` of `file`.
+    ///
+    /// `file` is matched against the end of the input paths, so `chapter.tex` finds
+    /// `chapters/chapter.tex` as well. Returns one area per page, covering all boxes produced by
+    /// the line.
+    pub fn output_at<P: AsRef<path::Path>>(&self, file: P, line: u32) -> Vec<OutputPosition> {
+        let file = file.as_ref();
+        let tags: Vec<u32> = self
+            .inputs
+            .iter()
+            .filter(|(_, path)| path.ends_with(file))
+            .map(|(tag, _)| *tag)
+            .collect();
+
+        let mut positions = Vec::new();
+        for (idx, records) in self.pages.iter().enumerate() {
+            let matching: Vec<&Record> = records
+                .iter()
+                .filter(|record| tags.contains(&record.tag) && record.line == line)
+                .collect();
+            let boxes: Vec<&Record> = matching
+                .iter()
+                .copied()
+                .filter(|record| record.kind == Kind::Box && record.width > 0.0)
+                .collect();
+            let covered = if boxes.is_empty() { matching } else { boxes };
+            if covered.is_empty() {
+                continue;
+            }
+
+            let left = covered.iter().map(|r| r.x).fold(f64::INFINITY, f64::min);
+            let top = covered
+                .iter()
+                .map(|r| r.y - r.height)
+                .fold(f64::INFINITY, f64::min);
+            let right = covered
+                .iter()
+                .map(|r| r.x + r.width)
+                .fold(f64::NEG_INFINITY, f64::max);
+            let bottom = covered
+                .iter()
+                .map(|r| r.y + r.depth)
+                .fold(f64::NEG_INFINITY, f64::max);
+
+            positions.push(OutputPosition {
+                page: idx as u32 + 1,
+                x: left,
+                y: top,
+                width: right - left,
+                height: bottom - top,
+            });
+        }
+
+        positions
+    }
+}
+
+/// Parses the part of a record after its marker, e.g. `1,3:4736286,49447968:100,20,5`.
+fn parse_record(rest: &str, kind: Kind, scale: &Scale) -> Option<Record> {
+    let mut parts = rest.split(':');
+    let mut link = parts.next()?.split(',');
+    let tag = link.next()?.parse().ok()?;
+    let line = link.next()?.parse().ok()?;
+
+    let mut point = parts.next()?.split(',');
+    let x = point.next()?.parse().ok()?;
+    let y = point.next()?.parse().ok()?;
+
+    let size: Vec<i64> = parts
+        .next()
+        .map(|size| size.split(',').filter_map(|v| v.parse().ok()).collect())
+        .unwrap_or_default();
+    let size = |idx: usize| size.get(idx).map_or(0.0, |&v| scale.length(v));
+
+    Some(Record {
+        tag,
+        line,
+        kind,
+        x: scale.x(x),
+        y: scale.y(y),
+        width: size(0),
+        height: size(1),
+        depth: size(2),
+    })
+}
+
+/// Turns an input path into one relative to the directory of the main file, if inside it.
+///
+/// TeX records files inside the project as `./chapter.tex`, or prefixed with the absolute path
+/// of the working directory, like `/tmp/texrender.abc/./input.tex`.
+fn normalize_input(file: &str) -> path::PathBuf {
+    let relative = match file.find("/./") {
+        Some(pos) => &file[pos + 3..],
+        None => file.strip_prefix("./").unwrap_or(file),
+    };
+    relative.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SourcePosition, SyncTex};
+    use flate2::{write::GzEncoder, Compression};
+    use std::{io::Write, path::Path};
+
+    const EXAMPLE: &str = "SyncTeX Version:1
+Input:1:/tmp/texrender.abc/./input.tex
+Input:2:/usr/share/texlive/texmf-dist/tex/latex/base/article.cls
+Input:3:./chapters/intro.tex
+Output:pdf
+Magnification:1000
+Unit:1
+X Offset:0
+Y Offset:0
+Content:
+!302
+{1
+[1,5:4736286,49447968:30000000,45000000,0
+(1,5:4736286,6578176:30000000,655360,131072
+g1,5:4736286,6578176
+x1,5:5262540,6578176
+)
+(3,2:4736286,13156352:30000000,655360,131072
+g3,2:4736286,13156352
+x3,3:9210572,13156352
+)
+]
+}1
+{2
+(3,7:4736286,6578176:30000000,655360,131072
+)
+}2
+Postamble:
+Count:12
+";
+
+    #[test]
+    fn parses_synctex() {
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(EXAMPLE.as_bytes()).unwrap();
+        let compressed = gz.finish().unwrap();
+
+        for data in &[EXAMPLE.as_bytes().to_vec(), compressed] {
+            let synctex = SyncTex::parse(data).unwrap();
+            assert_eq!(synctex.pages(), 2);
+            assert_eq!(
+                synctex.inputs(),
+                [
+                    Path::new("/usr/share/texlive/texmf-dist/tex/latex/base/article.cls"),
+                    Path::new("chapters/intro.tex"),
+                    Path::new("input.tex"),
+                ]
+            );
+        }
+
+        assert!(SyncTex::parse(b"%PDF-1.5").is_err());
+    }
+
+    #[test]
+    fn maps_output_to_source() {
+        let synctex = SyncTex::parse(EXAMPLE.as_bytes()).unwrap();
+
+        // 72pt from the left and 100pt (the second line's baseline) from the top.
+        assert_eq!(
+            synctex.source_at(1, 72.0, 100.0),
+            Some(SourcePosition {
+                file: "input.tex".into(),
+                line: 5
+            })
+        );
+        assert_eq!(
+            synctex.source_at(1, 200.0, 199.0),
+            Some(SourcePosition {
+                file: "chapters/intro.tex".into(),
+                line: 3
+            })
+        );
+        assert_eq!(synctex.source_at(3, 0.0, 0.0), None);
+    }
+
+    #[test]
+    fn maps_source_to_output() {
+        let synctex = SyncTex::parse(EXAMPLE.as_bytes()).unwrap();
+
+        let positions = synctex.output_at("intro.tex", 7);
+        assert_eq!(positions.len(), 1);
+        assert_eq!(positions[0].page, 2);
+        assert!((positions[0].x - 72.0).abs() < 0.01);
+        assert!((positions[0].y - 90.04).abs() < 0.01);
+
+        let positions = synctex.output_at("input.tex", 5);
+        assert_eq!(positions.len(), 1);
+        assert_eq!(positions[0].page, 1);
+        assert!(synctex.output_at("input.tex", 99).is_empty());
+    }
+}
@@ -0,0 +1,340 @@
+//! Toolchain discovery.
+//!
+//! `Toolchain::detect` looks up `latexmk`, the TeX engines and helper programs and queries their
+//! versions. `TexRender::preflight` checks that everything a particular rendering needs is
+//! installed, including packages and classes, which are looked up with `kpsewhich`. Both allow
+//! refusing to start with a broken TeX installation, instead of failing on the first rendering.
+
+use crate::{runner, RenderingError};
+use std::{env, ffi::OsStr, fmt, fs, path, process, time::Duration};
+
+/// Programs looked for by `Toolchain::detect`.
+pub const PROGRAMS: &[&str] = &[
+    "latexmk",
+    "pdflatex",
+    "xelatex",
+    "lualatex",
+    "latex",
+    "dvilualatex",
+    "bibtex",
+    "biber",
+    "dvips",
+    "dvisvgm",
+    "kpsewhich",
+    "tectonic",
+];
+
+/// Maximum time a program may take to report its version.
+const VERSION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A program found on the system.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Tool {
+    /// Name of the program, e.g. `pdflatex`.
+    pub name: String,
+    /// Location of the program.
+    pub path: path::PathBuf,
+    /// First line printed by the program when called with `--version`, if any.
+    pub version: Option<String>,
+}
+
+/// Programs found on the system.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Toolchain {
+    /// Programs found, in the order they were looked for.
+    pub tools: Vec<Tool>,
+    /// Programs not found, as they were looked for.
+    pub missing: Vec<String>,
+    /// Release of TeX Live, e.g. `2023`, if the programs belong to a TeX Live installation.
+    pub texlive_release: Option<u32>,
+}
+
+impl Toolchain {
+    /// Looks up all programs listed in `PROGRAMS` on `PATH`.
+    pub fn detect() -> Toolchain {
+        Self::detect_programs(PROGRAMS)
+    }
+
+    /// Looks up programs, given by name or path, on `PATH`.
+    pub fn detect_programs<I>(programs: I) -> Toolchain
+    where
+        I: IntoIterator,
+        I::Item: AsRef<path::Path>,
+    {
+        Self::probe(programs, env::var_os("PATH").as_deref())
+    }
+
+    /// Looks up programs on `search_path`, a list of directories in the format of `PATH`.
+    pub(crate) fn probe<I>(programs: I, search_path: Option<&OsStr>) -> Toolchain
+    where
+        I: IntoIterator,
+        I::Item: AsRef<path::Path>,
+    {
+        let mut toolchain = Toolchain::default();
+        for program in programs {
+            let program = program.as_ref();
+            let found = find_program(program, search_path);
+            let name = program.file_name().map(|name| name.to_string_lossy());
+
+            match (found, name) {
+                (Some(path), Some(name)) => {
+                    let version = query_version(&path);
+                    if toolchain.texlive_release.is_none() {
+                        toolchain.texlive_release = version.as_deref().and_then(texlive_release);
+                    }
+                    toolchain.tools.push(Tool {
+                        name: name.into_owned(),
+                        path,
+                        version,
+                    });
+                }
+                _ => toolchain.missing.push(program.display().to_string()),
+            }
+        }
+        toolchain
+    }
+
+    /// Returns a program found, by name.
+    pub fn tool(&self, name: &str) -> Option<&Tool> {
+        self.tools.iter().find(|tool| tool.name == name)
+    }
+
+    /// Looks up files like `hyperref.sty` or `article.cls` with `kpsewhich`.
+    ///
+    /// Returns the files that are not installed. Fails with `RenderingError::RunError` if
+    /// `kpsewhich` was not found.
+    pub fn missing_files<I, S>(&self, files: I) -> Result<Vec<String>, RenderingError>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let kpsewhich = self.tool("kpsewhich").ok_or_else(|| {
+            RenderingError::RunError(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "kpsewhich not found",
+            ))
+        })?;
+        let files: Vec<String> = files.into_iter().map(Into::into).collect();
+        missing_files(process::Command::new(&kpsewhich.path), &files)
+    }
+}
+
+/// Outcome of `TexRender::preflight`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PreflightReport {
+    /// Programs needed for rendering that were found, along with their versions.
+    pub toolchain: Toolchain,
+    /// Programs needed for rendering that were not found.
+    pub missing_programs: Vec<String>,
+    /// Required packages and classes that are not installed.
+    ///
+    /// Files are not checked if `kpsewhich` is missing.
+    pub missing_files: Vec<String>,
+}
+
+impl PreflightReport {
+    /// Returns whether everything needed for rendering is installed.
+    pub fn is_ok(&self) -> bool {
+        self.missing_programs.is_empty() && self.missing_files.is_empty()
+    }
+}
+
+impl fmt::Display for PreflightReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_ok() {
+            return f.write_str("toolchain is complete");
+        }
+
+        let mut problems = Vec::new();
+        if !self.missing_programs.is_empty() {
+            problems.push(format!(
+                "missing programs: {}",
+                self.missing_programs.join(", ")
+            ));
+        }
+        if !self.missing_files.is_empty() {
+            problems.push(format!("missing files: {}", self.missing_files.join(", ")));
+        }
+        f.write_str(&problems.join("; "))
+    }
+}
+
+/// Runs `kpsewhich`, set up as `cmd`, to look up `files`, returning those not found.
+pub(crate) fn missing_files(
+    mut cmd: process::Command,
+    files: &[String],
+) -> Result<Vec<String>, RenderingError> {
+    if files.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    cmd.args(files);
+    let supervision = runner::Supervision {
+        timeout: Some(VERSION_TIMEOUT),
+        ..runner::Supervision::default()
+    };
+    // `kpsewhich` exits unsuccessfully if any file is missing, its output tells which.
+    let output = runner::run(cmd, &supervision)?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let found: Vec<&OsStr> = stdout
+        .lines()
+        .filter_map(|line| path::Path::new(line.trim()).file_name())
+        .collect();
+
+    Ok(files
+        .iter()
+        .filter(|file| {
+            let name = path::Path::new(file.as_str()).file_name();
+            !found.iter().any(|found| Some(*found) == name)
+        })
+        .cloned()
+        .collect())
+}
+
+/// Finds a program, given by name or path, returning its location.
+///
+/// Names are looked up in the directories of `search_path`.
+fn find_program(program: &path::Path, search_path: Option<&OsStr>) -> Option<path::PathBuf> {
+    if program.components().count() > 1 {
+        return Some(program.to_owned()).filter(|path| is_executable(path));
+    }
+
+    env::split_paths(search_path?)
+        .flat_map(|dir| {
+            let candidate = dir.join(program);
+            let exe = candidate.with_extension(env::consts::EXE_EXTENSION);
+            [candidate, exe]
+        })
+        .find(|candidate| is_executable(candidate))
+}
+
+/// Checks whether `path` is an executable file.
+fn is_executable(path: &path::Path) -> bool {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        _ => return false,
+    };
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.permissions().mode() & 0o111 != 0
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        true
+    }
+}
+
+/// Runs a program with `--version`, returning the first line it printed.
+fn query_version(program: &path::Path) -> Option<String> {
+    let mut cmd = process::Command::new(program);
+    cmd.arg("--version");
+    let supervision = runner::Supervision {
+        timeout: Some(VERSION_TIMEOUT),
+        ..runner::Supervision::default()
+    };
+    let output = runner::run(cmd, &supervision).ok()?;
+
+    [&output.stdout, &output.stderr].iter().find_map(|out| {
+        String::from_utf8_lossy(out)
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty())
+            .map(str::to_owned)
+    })
+}
+
+/// Extracts the TeX Live release from a version line, e.g. `pdfTeX 3.14 (TeX Live 2023/Debian)`.
+fn texlive_release(version: &str) -> Option<u32> {
+    let rest = &version[version.find("TeX Live ")? + "TeX Live ".len()..];
+    let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+    rest[..digits].parse().ok().filter(|_| digits == 4)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{texlive_release, Toolchain};
+
+    #[cfg(unix)]
+    fn script(dir: &std::path::Path, name: &str, body: &str, mode: u32) {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = dir.join(name);
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[test]
+    fn parses_texlive_release() {
+        assert_eq!(
+            texlive_release("pdfTeX 3.141592653-2.6-1.40.25 (TeX Live 2023/Debian)"),
+            Some(2023)
+        );
+        assert_eq!(texlive_release("BibTeX 0.99d (TeX Live 2022)"), Some(2022));
+        assert_eq!(texlive_release("MiKTeX-pdfTeX 4.10 (MiKTeX 22.1)"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn finds_programs_and_versions() {
+        let dir = tempdir::TempDir::new("texrender-test").unwrap();
+        script(
+            dir.path(),
+            "pdflatex",
+            "echo 'pdfTeX 3.141592653-2.6-1.40.25 (TeX Live 2023)'",
+            0o755,
+        );
+        script(
+            dir.path(),
+            "latexmk",
+            "echo; echo 'Latexmk, Version 4.79'",
+            0o755,
+        );
+        script(dir.path(), "biber", "exit 0", 0o644);
+
+        let toolchain = Toolchain::probe(
+            ["latexmk", "pdflatex", "biber", "xelatex"],
+            Some(dir.path().as_os_str()),
+        );
+        assert_eq!(toolchain.tools.len(), 2);
+        assert_eq!(toolchain.tools[0].name, "latexmk");
+        assert_eq!(toolchain.tools[0].path, dir.path().join("latexmk"));
+        assert_eq!(
+            toolchain.tools[0].version.as_deref(),
+            Some("Latexmk, Version 4.79")
+        );
+        assert_eq!(toolchain.tool("pdflatex").unwrap().name, "pdflatex");
+        assert_eq!(toolchain.missing, ["biber", "xelatex"]);
+        assert_eq!(toolchain.texlive_release, Some(2023));
+
+        let by_path = Toolchain::probe([dir.path().join("pdflatex")], None);
+        assert_eq!(by_path.tools.len(), 1);
+        assert!(Toolchain::probe(["pdflatex"], None).tools.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn checks_files_with_kpsewhich() {
+        let dir = tempdir::TempDir::new("texrender-test").unwrap();
+        script(
+            dir.path(),
+            "kpsewhich",
+            "for f; do [ \"$f\" = article.cls ] && echo /texmf/tex/latex/base/article.cls; done; \
+             exit 1",
+            0o755,
+        );
+
+        let toolchain = Toolchain::probe(["kpsewhich"], Some(dir.path().as_os_str()));
+        assert_eq!(
+            toolchain
+                .missing_files(["article.cls", "nonexistent.sty"])
+                .unwrap(),
+            ["nonexistent.sty"]
+        );
+        assert!(Toolchain::default().missing_files(["article.cls"]).is_err());
+    }
+}
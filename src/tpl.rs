@@ -65,15 +65,38 @@
 //! Element functions like `section` above typically cover most use cases, while not preventing the
 //! u ser to drop back to the raw functions above. The `elems` macro conveniently boxes and
 //! type-erases children, while `N` can be used for "no arguments" for both args and optargs.
+//!
+//! ## Tracing errors back to elements
+//!
+//! `render_mapped` additionally returns a `SourceMap`, recording which element produced each line.
+//! Elements can be tagged to make them easier to identify:
+//!
+//! ```rust
+//! use texrender::elems;
+//! use texrender::tpl::TexElement;
+//! use texrender::tpl::elements::{doc, document, raw};
+//!
+//! let tex = doc(elems!(document(elems!(raw("\\foo").tagged("greeting")))));
+//! let (output, map) = tex.render_mapped().expect("rendering failed");
+//!
+//! let path = map.path_at(2).expect("line not mapped");
+//! assert_eq!(path.tag(), Some("greeting"));
+//! assert_eq!(path.to_string(), "Group > \\begin{document} > RawTex (greeting)");
+//! ```
 
 #[macro_use]
 pub mod macros;
 
 pub mod elements;
+pub mod source_map;
 
+use source_map::{Mapper, SourceMap};
 use std::fmt::Debug;
 use std::io::Write;
-use std::{io, string};
+use std::{any, io, string};
+
+/// Callback writing a child element, see `TexElement::write_tex_with`.
+pub type WriteChild<'a> = dyn FnMut(&dyn TexElement, &mut dyn Write) -> io::Result<()> + 'a;
 
 /// Renderable Tex element.
 pub trait TexElement: Debug {
@@ -97,6 +120,62 @@ pub trait TexElement: Debug {
 
     /// Writes a rendering of the element to the given writer.
     fn write_tex(&self, writer: &mut dyn Write) -> io::Result<()>;
+
+    /// Writes a rendering of the element, writing child elements through `child`.
+    ///
+    /// Elements containing other elements should implement this method and have `write_tex`
+    /// delegate to it, so that their children show up in source maps. By default, the element is
+    /// written as a whole.
+    fn write_tex_with(&self, writer: &mut dyn Write, child: &mut WriteChild<'_>) -> io::Result<()> {
+        let _ = child;
+        self.write_tex(writer)
+    }
+
+    /// Returns the name of the element used in source maps.
+    ///
+    /// Defaults to the name of the type.
+    fn name(&self) -> String {
+        let name = any::type_name::<Self>();
+        let name = name.split('<').next().unwrap_or(name);
+        name.rsplit("::").next().unwrap_or(name).to_owned()
+    }
+
+    /// Returns the tag of the element, see `tagged`.
+    fn tag(&self) -> Option<&str> {
+        None
+    }
+
+    /// Attaches a tag to the element, identifying it in source maps.
+    fn tagged<S: Into<String>>(self, tag: S) -> Tagged
+    where
+        Self: Sized + 'static,
+    {
+        Tagged {
+            tag: tag.into(),
+            inner: self.boxed(),
+        }
+    }
+
+    /// Renders the element into a string, recording which element produced each line.
+    ///
+    /// May return an error if a non-utf8 element has been given.
+    fn render_mapped(&self) -> Result<(String, SourceMap), string::FromUtf8Error> {
+        let mut buffer: Vec<u8> = Vec::new();
+        let map = self
+            .write_tex_mapped(&mut buffer)
+            .expect("should always be able to write to in-memory buffer");
+        Ok((String::from_utf8(buffer)?, map))
+    }
+
+    /// Writes a rendering of the element, recording which element produced each line.
+    fn write_tex_mapped(&self, writer: &mut dyn Write) -> io::Result<SourceMap> {
+        Mapper::write(self, writer)
+    }
+}
+
+/// Writes a child element without recording it.
+fn write_unmapped(child: &dyn TexElement, writer: &mut dyn Write) -> io::Result<()> {
+    child.write_tex(writer)
 }
 
 /// Conversion trait for various types.
@@ -122,7 +201,7 @@ impl IntoTexElement for Box<dyn TexElement> {
     }
 }
 
-impl<'a> IntoTexElement for &'a str {
+impl IntoTexElement for &str {
     #[inline]
     fn into_tex_element(self) -> Box<dyn TexElement> {
         self.to_owned().into_tex_element()
@@ -182,6 +261,21 @@ using_display!(f64);
 
 /// Writes a list of tex elements to a stream with a separator.
 pub fn write_list<'a, I>(writer: &mut dyn Write, separator: &str, iter: I) -> io::Result<()>
+where
+    I: Iterator<Item = &'a Box<dyn TexElement>> + 'a,
+{
+    write_list_with(writer, separator, iter, &mut write_unmapped)
+}
+
+/// Writes a list of tex elements through `child`, with a separator.
+///
+/// See `TexElement::write_tex_with`.
+pub fn write_list_with<'a, I>(
+    writer: &mut dyn Write,
+    separator: &str,
+    iter: I,
+    child: &mut WriteChild<'_>,
+) -> io::Result<()>
 where
     I: Iterator<Item = &'a Box<dyn TexElement>> + 'a,
 {
@@ -189,7 +283,7 @@ where
         if idx != 0 {
             writer.write_all(separator.as_bytes())?;
         }
-        arg.write_tex(writer)?;
+        child(arg.as_ref(), writer)?;
     }
 
     Ok(())
@@ -259,9 +353,13 @@ impl OptArgs {
 
 impl TexElement for OptArgs {
     fn write_tex(&self, writer: &mut dyn Write) -> io::Result<()> {
+        self.write_tex_with(writer, &mut write_unmapped)
+    }
+
+    fn write_tex_with(&self, writer: &mut dyn Write, child: &mut WriteChild<'_>) -> io::Result<()> {
         if !self.0.is_empty() {
             writer.write_all(b"[")?;
-            write_list(writer, ",", self.0.iter())?;
+            write_list_with(writer, ",", self.0.iter(), child)?;
             writer.write_all(b"]")?;
         }
 
@@ -292,9 +390,13 @@ impl Args {
 
 impl TexElement for Args {
     fn write_tex(&self, writer: &mut dyn Write) -> io::Result<()> {
+        self.write_tex_with(writer, &mut write_unmapped)
+    }
+
+    fn write_tex_with(&self, writer: &mut dyn Write, child: &mut WriteChild<'_>) -> io::Result<()> {
         if !self.0.is_empty() {
             writer.write_all(b"{")?;
-            write_list(writer, "}{", self.0.iter())?;
+            write_list_with(writer, "}{", self.0.iter(), child)?;
             writer.write_all(b"}")?;
         }
 
@@ -345,15 +447,24 @@ impl MacroCall {
 
 impl TexElement for MacroCall {
     fn write_tex(&self, writer: &mut dyn Write) -> io::Result<()> {
+        self.write_tex_with(writer, &mut write_unmapped)
+    }
+
+    fn write_tex_with(&self, writer: &mut dyn Write, child: &mut WriteChild<'_>) -> io::Result<()> {
         writer.write_all(br"\")?;
         self.ident.write_tex(writer)?;
-        self.opt_args.write_tex(writer)?;
-        self.args.write_tex(writer)?;
+        // Arguments are children of the call itself.
+        self.opt_args.write_tex_with(writer, child)?;
+        self.args.write_tex_with(writer, child)?;
         if self.newline {
             writer.write_all(b"\n")?;
         }
         Ok(())
     }
+
+    fn name(&self) -> String {
+        format!("\\{}", render_lossy(self.ident.as_ref()))
+    }
 }
 
 /// A block with a begin and end instruction.
@@ -390,16 +501,20 @@ impl BeginEndBlock {
 
 impl TexElement for BeginEndBlock {
     fn write_tex(&self, writer: &mut dyn Write) -> io::Result<()> {
+        self.write_tex_with(writer, &mut write_unmapped)
+    }
+
+    fn write_tex_with(&self, writer: &mut dyn Write, child: &mut WriteChild<'_>) -> io::Result<()> {
         writer.write_all(b"\\begin{")?;
         self.ident.write_tex(writer)?;
         writer.write_all(b"}")?;
 
-        self.opt_args.write_tex(writer)?;
-        self.args.write_tex(writer)?;
+        self.opt_args.write_tex_with(writer, child)?;
+        self.args.write_tex_with(writer, child)?;
         writer.write_all(b"\n")?;
 
-        for child in &self.children {
-            child.write_tex(writer)?;
+        for elem in &self.children {
+            child(elem.as_ref(), writer)?;
         }
 
         writer.write_all(b"\n\\end{")?;
@@ -407,6 +522,10 @@ impl TexElement for BeginEndBlock {
         writer.write_all(b"}\n")?;
         Ok(())
     }
+
+    fn name(&self) -> String {
+        format!("\\begin{{{}}}", render_lossy(self.ident.as_ref()))
+    }
 }
 
 /// An anonymous block.
@@ -424,9 +543,13 @@ impl AnonymousBlock {
 
 impl TexElement for AnonymousBlock {
     fn write_tex(&self, writer: &mut dyn Write) -> io::Result<()> {
+        self.write_tex_with(writer, &mut write_unmapped)
+    }
+
+    fn write_tex_with(&self, writer: &mut dyn Write, child: &mut WriteChild<'_>) -> io::Result<()> {
         writer.write_all(b"{")?;
-        for child in &self.0 {
-            child.write_tex(writer)?;
+        for elem in &self.0 {
+            child(elem.as_ref(), writer)?;
         }
         writer.write_all(b"}")?;
         Ok(())
@@ -449,8 +572,12 @@ impl Group {
 
 impl TexElement for Group {
     fn write_tex(&self, writer: &mut dyn Write) -> io::Result<()> {
-        for child in &self.0 {
-            child.write_tex(writer)?;
+        self.write_tex_with(writer, &mut write_unmapped)
+    }
+
+    fn write_tex_with(&self, writer: &mut dyn Write, child: &mut WriteChild<'_>) -> io::Result<()> {
+        for elem in &self.0 {
+            child(elem.as_ref(), writer)?;
         }
         Ok(())
     }
@@ -471,7 +598,49 @@ impl TableRow {
 
 impl TexElement for TableRow {
     fn write_tex(&self, writer: &mut dyn Write) -> io::Result<()> {
-        write_list(writer, " & ", self.0.iter())?;
+        self.write_tex_with(writer, &mut write_unmapped)
+    }
+
+    fn write_tex_with(&self, writer: &mut dyn Write, child: &mut WriteChild<'_>) -> io::Result<()> {
+        write_list_with(writer, " & ", self.0.iter(), child)?;
         writer.write_all(b"\\\\\n")
     }
 }
+
+/// An element with a tag attached.
+///
+/// Tags identify elements in source maps; when written, a tagged element is identical to the
+/// element itself. Created through `TexElement::tagged`.
+#[derive(Debug)]
+pub struct Tagged {
+    /// The tag.
+    tag: String,
+    /// The tagged element.
+    inner: Box<dyn TexElement>,
+}
+
+impl TexElement for Tagged {
+    fn write_tex(&self, writer: &mut dyn Write) -> io::Result<()> {
+        self.inner.write_tex(writer)
+    }
+
+    fn write_tex_with(&self, writer: &mut dyn Write, child: &mut WriteChild<'_>) -> io::Result<()> {
+        self.inner.write_tex_with(writer, child)
+    }
+
+    fn name(&self) -> String {
+        self.inner.name()
+    }
+
+    fn tag(&self) -> Option<&str> {
+        Some(&self.tag)
+    }
+}
+
+/// Renders an element for display purposes, replacing invalid UTF-8.
+fn render_lossy(elem: &dyn TexElement) -> String {
+    let mut buffer: Vec<u8> = Vec::new();
+    elem.write_tex(&mut buffer)
+        .expect("should always be able to write to in-memory buffer");
+    String::from_utf8_lossy(&buffer).into_owned()
+}
@@ -0,0 +1,330 @@
+//! Source maps for generated documents.
+//!
+//! A `SourceMap` records which element of a template produced each line of the output, see
+//! `TexElement::render_mapped`. Errors reported by TeX for a line of the generated document can
+//! thus be traced back to the element, and any tag attached to it through `TexElement::tagged`.
+
+use super::TexElement;
+use crate::{report, RenderingError};
+use std::{cell::Cell, cell::RefCell, fmt, io, io::Write, path};
+
+/// An element recorded in a source map.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MappedElement {
+    /// Name of the element, e.g. `\section` or `\begin{document}`.
+    pub name: String,
+    /// Tag attached through `TexElement::tagged`, if any.
+    pub tag: Option<String>,
+    /// First line written by the element, starting at 1.
+    pub first_line: u32,
+    /// Last line written by the element.
+    pub last_line: u32,
+    /// Index of the enclosing element, `None` for the root.
+    pub parent: Option<usize>,
+}
+
+impl MappedElement {
+    /// Returns whether the element wrote to `line`.
+    fn contains(&self, line: u32) -> bool {
+        self.first_line <= line && line <= self.last_line
+    }
+}
+
+impl fmt::Display for MappedElement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)?;
+        if let Some(ref tag) = self.tag {
+            write!(f, " ({})", tag)?;
+        }
+        Ok(())
+    }
+}
+
+/// Elements leading from the root of a document to an element inside it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ElementPath<'a>(pub Vec<&'a MappedElement>);
+
+impl ElementPath<'_> {
+    /// Returns the innermost element of the path.
+    pub fn element(&self) -> Option<&MappedElement> {
+        self.0.last().copied()
+    }
+
+    /// Returns the tag of the innermost tagged element of the path.
+    pub fn tag(&self) -> Option<&str> {
+        self.0.iter().rev().find_map(|elem| elem.tag.as_deref())
+    }
+}
+
+impl fmt::Display for ElementPath<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (idx, elem) in self.0.iter().enumerate() {
+            if idx != 0 {
+                f.write_str(" > ")?;
+            }
+            write!(f, "{}", elem)?;
+        }
+        Ok(())
+    }
+}
+
+/// Map from lines of a generated document to the elements that produced them.
+///
+/// Elements that did not write anything are not recorded.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SourceMap(Vec<MappedElement>);
+
+impl SourceMap {
+    /// Returns all recorded elements, parents preceding their children.
+    pub fn elements(&self) -> &[MappedElement] {
+        &self.0
+    }
+
+    /// Returns the path to the innermost element that wrote to `line`.
+    ///
+    /// If several elements wrote to the line, e.g. inline elements next to each other, the first
+    /// one is picked.
+    pub fn path_at(&self, line: u32) -> Option<ElementPath<'_>> {
+        let mut current = None;
+        for (idx, elem) in self.0.iter().enumerate() {
+            // Children follow their parents, so descending only requires looking further ahead.
+            if elem.contains(line) && elem.parent == current {
+                current = Some(idx);
+            }
+        }
+
+        let mut path = Vec::new();
+        while let Some(idx) = current {
+            path.push(&self.0[idx]);
+            current = self.0[idx].parent;
+        }
+        path.reverse();
+
+        Some(ElementPath(path)).filter(|path| !path.0.is_empty())
+    }
+
+    /// Returns the path to the element that caused a rendering error.
+    ///
+    /// `file` is the name the mapped document was rendered as, e.g. `input.tex` for
+    /// `TexRender::from_bytes`. Returns `None` if the error does not point at a line of `file`.
+    pub fn locate_error<P: AsRef<path::Path>>(
+        &self,
+        error: &RenderingError,
+        file: P,
+    ) -> Option<ElementPath<'_>> {
+        let (error_file, line) = report::error_location(error)?;
+        if !report::same_file(&error_file, file.as_ref()) {
+            return None;
+        }
+        self.path_at(line)
+    }
+}
+
+/// Position in the output of a mapped rendering.
+#[derive(Copy, Clone, Debug, Default)]
+struct Position {
+    /// Number of newlines written.
+    newlines: u32,
+    /// Number of bytes written.
+    bytes: u64,
+    /// Whether the last byte written was a newline.
+    at_line_start: bool,
+}
+
+/// Writer keeping track of the position in the output.
+struct Counter<'a> {
+    /// Writer receiving the output.
+    inner: &'a mut dyn Write,
+    /// Current position.
+    position: &'a Cell<Position>,
+}
+
+impl Write for Counter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        if len > 0 {
+            let mut position = self.position.get();
+            position.newlines += buf[..len].iter().filter(|&&b| b == b'\n').count() as u32;
+            position.bytes += len as u64;
+            position.at_line_start = buf[len - 1] == b'\n';
+            self.position.set(position);
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Records elements while they are written.
+#[derive(Default)]
+pub(super) struct Mapper {
+    /// Position in the output.
+    position: Cell<Position>,
+    /// Elements recorded so far.
+    elements: RefCell<Vec<MappedElement>>,
+    /// Index of the element currently being written.
+    current: Cell<Option<usize>>,
+}
+
+impl Mapper {
+    /// Writes `root` to `writer`, returning the source map.
+    pub(super) fn write<E: TexElement + ?Sized>(
+        root: &E,
+        writer: &mut dyn Write,
+    ) -> io::Result<SourceMap> {
+        let mapper = Mapper::default();
+        let mut counter = Counter {
+            inner: writer,
+            position: &mapper.position,
+        };
+        mapper.record(root.name(), root.tag(), &mut counter, |writer| {
+            root.write_tex_with(writer, &mut |child, writer| {
+                mapper.write_child(child, writer)
+            })
+        })?;
+
+        let mut elements = mapper.elements.into_inner();
+        // Drop elements that wrote nothing, fixing up the indices of the remaining parents.
+        let mut indices = Vec::with_capacity(elements.len());
+        let mut kept = 0;
+        for elem in &elements {
+            indices.push(kept);
+            if elem.first_line <= elem.last_line {
+                kept += 1;
+            }
+        }
+        elements.retain(|elem| elem.first_line <= elem.last_line);
+        for elem in &mut elements {
+            elem.parent = elem.parent.map(|idx| indices[idx]);
+        }
+
+        Ok(SourceMap(elements))
+    }
+
+    /// Writes a child element.
+    fn write_child(&self, child: &dyn TexElement, writer: &mut dyn Write) -> io::Result<()> {
+        self.record(child.name(), child.tag(), writer, |writer| {
+            child.write_tex_with(writer, &mut |child, writer| self.write_child(child, writer))
+        })
+    }
+
+    /// Records an element written by `write`.
+    fn record<F>(
+        &self,
+        name: String,
+        tag: Option<&str>,
+        writer: &mut dyn Write,
+        write: F,
+    ) -> io::Result<()>
+    where
+        F: FnOnce(&mut dyn Write) -> io::Result<()>,
+    {
+        let start = self.position.get();
+        let parent = self.current.get();
+        let idx = {
+            let mut elements = self.elements.borrow_mut();
+            elements.push(MappedElement {
+                name,
+                tag: tag.map(str::to_owned),
+                first_line: 0,
+                last_line: 0,
+                parent,
+            });
+            elements.len() - 1
+        };
+
+        self.current.set(Some(idx));
+        let result = write(writer);
+        self.current.set(parent);
+
+        let end = self.position.get();
+        let mut elements = self.elements.borrow_mut();
+        let elem = &mut elements[idx];
+        if end.bytes == start.bytes {
+            // Marks the element as empty.
+            elem.first_line = 1;
+        } else {
+            // An element starting right after a newline starts on the next line.
+            elem.first_line = start.newlines + 1;
+            elem.last_line = if end.at_line_start {
+                end.newlines
+            } else {
+                end.newlines + 1
+            };
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tpl::elements::{doc, document, documentclass, raw, section};
+    use crate::tpl::TexElement;
+    use crate::{diagnostics::parse_log, RenderingError};
+
+    #[test]
+    fn maps_lines_to_elements() {
+        let tex = doc(vec![
+            documentclass(Vec::new(), "article").boxed(),
+            document(vec![
+                section("Intro").boxed(),
+                raw("all\ngood\n").tagged("body").boxed(),
+                section("Outro").boxed(),
+            ])
+            .boxed(),
+        ]);
+
+        let (output, map) = tex.render_mapped().unwrap();
+        assert_eq!(output, tex.render().unwrap());
+        assert_eq!(
+            output,
+            "\\documentclass{article}
+\\begin{document}
+\\section{Intro}
+all
+good
+\\section{Outro}
+
+\\end{document}
+"
+        );
+
+        let path = |line| map.path_at(line).map(|path| path.to_string());
+        assert_eq!(path(1).as_deref(), Some("Group > \\documentclass > Text"));
+        assert_eq!(
+            path(3).as_deref(),
+            Some("Group > \\begin{document} > \\section > Text")
+        );
+        assert_eq!(
+            path(5).as_deref(),
+            Some("Group > \\begin{document} > RawTex (body)")
+        );
+        assert_eq!(path(7).as_deref(), Some("Group > \\begin{document}"));
+        assert_eq!(path(9), None);
+    }
+
+    #[test]
+    fn locates_errors() {
+        let tex = doc(vec![
+            raw("\\documentclass{article}\n\\begin{document}\n").boxed(),
+            raw("\\foo\n").tagged("broken").boxed(),
+            raw("\\end{document}\n").boxed(),
+        ]);
+        let (_, map) = tex.render_mapped().unwrap();
+
+        let error = RenderingError::LatexError {
+            status: Some(12),
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            diagnostics: parse_log(b"./input.tex:3: Undefined control sequence.\nl.3 \\foo\n"),
+        };
+
+        let path = map.locate_error(&error, "input.tex").unwrap();
+        assert_eq!(path.tag(), Some("broken"));
+        assert_eq!(path.to_string(), "Group > RawTex (broken)");
+        assert_eq!(map.locate_error(&error, "other.tex"), None);
+    }
+}
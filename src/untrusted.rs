@@ -0,0 +1,189 @@
+//! Checks for rendering untrusted input.
+//!
+//! Untrusted documents are rendered with shell escape disabled and paranoid kpathsea settings,
+//! which is what actually keeps them contained. The checks in this module additionally refuse
+//! documents that obviously try to break out, and detect file accesses kpathsea refused, so they
+//! can be reported as such instead of as a generic LaTeX error.
+//!
+//! Scanning the source is best effort only: TeX allows redefining its syntax at runtime (e.g.
+//! through `\catcode`), so no scanner can find every construct.
+
+/// Control sequences refused in untrusted input.
+///
+/// These run shell commands or arbitrary Lua code, which is not subject to kpathsea restrictions.
+const FORBIDDEN_COMMANDS: &[&str] = &[
+    "ShellEscape",
+    "DelayedShellEscape",
+    "directlua",
+    "latelua",
+    "luaexec",
+    "luadirect",
+];
+
+/// Environments refused in untrusted input.
+const FORBIDDEN_ENVIRONMENTS: &[&str] = &["luacode", "luacode*"];
+
+/// Finds the first forbidden construct in a source file.
+///
+/// Returns its line number along with a description. Comments are ignored.
+pub(crate) fn find_forbidden_construct(source: &[u8]) -> Option<(u32, String)> {
+    let source = String::from_utf8_lossy(source);
+
+    for (idx, line) in source.lines().enumerate() {
+        let line = strip_comment(line);
+
+        let mut rest = line;
+        while let Some(pos) = rest.find('\\') {
+            let after = &rest[pos + 1..];
+            let name_len = after
+                .find(|c: char| !c.is_ascii_alphabetic())
+                .unwrap_or(after.len());
+            let (name, args) = after.split_at(name_len);
+            rest = if name.is_empty() {
+                // Skip control symbols like `\\` or `\%`.
+                let mut chars = after.chars();
+                chars.next();
+                chars.as_str()
+            } else {
+                args
+            };
+
+            if let Some(construct) = check_command(name, args) {
+                return Some((idx as u32 + 1, construct));
+            }
+        }
+    }
+
+    None
+}
+
+/// Checks a single control sequence, followed by the rest of its line.
+fn check_command(name: &str, args: &str) -> Option<String> {
+    if FORBIDDEN_COMMANDS.contains(&name) {
+        return Some(format!("\\{}", name));
+    }
+
+    match name {
+        "csname" => {
+            // `\csname directlua\endcsname` runs `\directlua` without spelling it out.
+            let built = args.split("\\endcsname").next().unwrap_or(args).trim();
+            if FORBIDDEN_COMMANDS.contains(&built) {
+                return Some(format!("\\csname {}\\endcsname", built));
+            }
+        }
+        "write" => {
+            // `\write18`, `\write 18` and `\write=18` all write to the shell.
+            let stream = args.trim_start_matches([' ', '=']);
+            if stream.starts_with("18") && !stream[2..].starts_with(|c: char| c.is_ascii_digit()) {
+                return Some("\\write18".to_owned());
+            }
+        }
+        "input" | "openin" | "openout" | "include" | "InputIfFileExists" => {
+            // Piped input, e.g. `\input|"ls"`, runs a shell command.
+            let target = args.trim_start_matches(|c: char| {
+                c == ' ' || c == '{' || c == '"' || c == '=' || c.is_ascii_digit()
+            });
+            if target.starts_with('|') {
+                return Some(format!("piped \\{}", name));
+            }
+        }
+        "begin" => {
+            let env = args
+                .trim_start()
+                .strip_prefix('{')
+                .and_then(|env| env.split('}').next())?;
+            if FORBIDDEN_ENVIRONMENTS.contains(&env) {
+                return Some(format!("{} environment", env));
+            }
+        }
+        _ => (),
+    }
+
+    None
+}
+
+/// Removes a comment from a line of TeX.
+fn strip_comment(line: &str) -> &str {
+    let mut escaped = false;
+    for (pos, c) in line.char_indices() {
+        match c {
+            '%' if !escaped => return &line[..pos],
+            '\\' => escaped = !escaped,
+            _ => escaped = false,
+        }
+    }
+    line
+}
+
+/// Extracts the paths of file accesses refused by kpathsea from process output.
+///
+/// kpathsea reports these as e.g. `pdflatex: Not reading from /etc/passwd (openin_any = p).`
+pub(crate) fn refused_accesses(output: &[u8]) -> Vec<String> {
+    let mut paths = Vec::new();
+
+    for line in String::from_utf8_lossy(output).lines() {
+        for marker in &["Not reading from ", "Not writing to "] {
+            let path = line
+                .find(marker)
+                .map(|pos| &line[pos + marker.len()..])
+                .and_then(|rest| rest.rfind(" (open").map(|end| &rest[..end]));
+            if let Some(path) = path {
+                if !paths.iter().any(|p| p == path) {
+                    paths.push(path.to_owned());
+                }
+            }
+        }
+    }
+
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_forbidden_construct, refused_accesses};
+
+    #[test]
+    fn finds_forbidden_constructs() {
+        let forbidden: &[(&str, &str)] = &[
+            (r"\immediate\write18{rm -rf /}", r"\write18"),
+            (r"\write 18{ls}", r"\write18"),
+            (r"\ShellEscape{ls}", r"\ShellEscape"),
+            (r#"\input|"ls /""#, r"piped \input"),
+            (r"\directlua{os.execute('ls')}", r"\directlua"),
+            (
+                r"\csname directlua\endcsname{os.execute('ls')}",
+                r"\csname directlua\endcsname",
+            ),
+            (r"\begin{luacode*}", "luacode* environment"),
+        ];
+        for (source, construct) in forbidden {
+            let doc = format!("\\documentclass{{article}}\n{}\n", source);
+            assert_eq!(
+                find_forbidden_construct(doc.as_bytes()),
+                Some((2, construct.to_string())),
+                "{}",
+                source
+            );
+        }
+
+        let allowed = br"
+        \documentclass{article}
+        % \write18{ls} is fine in a comment
+        \newwrite\out \immediate\write\out{18} \write1{x}
+        50\% \\ \input{chapter}
+        ";
+        assert_eq!(find_forbidden_construct(allowed), None);
+    }
+
+    #[test]
+    fn extracts_refused_accesses() {
+        let stderr = b"\
+Latexmk: applying rule 'pdflatex'...
+pdflatex: Not reading from /etc/passwd (openin_any = p).
+pdflatex: Not writing to ../escape.txt (openout_any = p).
+pdflatex: Not reading from /etc/passwd (openin_any = p).
+";
+        assert_eq!(refused_accesses(stderr), ["/etc/passwd", "../escape.txt"]);
+        assert!(refused_accesses(b"Output written on input.pdf").is_empty());
+    }
+}